@@ -0,0 +1,376 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::io::{Error, ErrorKind};
+
+/// The environment variable that holds alias overrides, as `name=value` pairs separated by `;`.
+pub const ENVIRONMENT_ALIASES: &str = "AUTOCOPIER_ALIASES";
+
+/// How aliases are written in paths.
+///
+/// In both syntaxes `@{name}` refers to an alias and `@@` is a literal `@`. The legacy syntax
+/// also accepts `@name`, where the longest alias name that matches wins. In the braced syntax
+/// any other `@` is kept as is.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasSyntax {
+    #[default]
+    Legacy,
+    Braced,
+}
+
+fn is_alias_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+/// A problem found while substituting aliases.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AliasError {
+    /// No alias with this name exists. It is left in the path as it was written.
+    Unresolved(String),
+    /// The alias refers back to itself, through the aliases in between.
+    Cycle(Vec<String>),
+    /// A `${ENV:NAME}` refers to an environment variable that is not set.
+    MissingEnvironmentVariable(String),
+}
+
+/// Parses an alias override written as `name=value`.
+pub fn parse_assignment(assignment: &str) -> Result<(String, String), Error> {
+    match assignment.find('=') {
+        Some(index) if index > 0 => Ok((
+            assignment[..index].to_owned(),
+            assignment[index + 1..].to_owned(),
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Alias should be written as name=value, got {}", assignment),
+        )),
+    }
+}
+
+/// Combines the alias overrides from the environment and from the command line. Both override
+/// the aliases in the configuration, and the command line overrides the environment.
+pub fn overrides(
+    environment: Option<&str>,
+    command_line: &[String],
+) -> Result<HashMap<String, String>, Error> {
+    let mut alias_map: HashMap<String, String> = HashMap::new();
+    let environment_assignments = environment
+        .into_iter()
+        .flat_map(|e| e.split(';'))
+        .filter(|a| !a.is_empty());
+    for assignment in environment_assignments.chain(command_line.iter().map(|a| a.as_str())) {
+        let (name, value) = parse_assignment(assignment)?;
+        alias_map.insert(name, value);
+    }
+    Ok(alias_map)
+}
+
+/// Replaces every alias in `path`, including the aliases used in the replacements themselves.
+pub fn substitute(
+    path: &str,
+    alias_map: &HashMap<String, String>,
+    syntax: AliasSyntax,
+) -> (String, Vec<AliasError>) {
+    let mut errors: Vec<AliasError> = Vec::new();
+    let result = expand(path, alias_map, syntax, &mut Vec::new(), &mut errors);
+    (result, errors)
+}
+
+/// Finds every cycle between the aliases, each reported once.
+pub fn find_cycles(alias_map: &HashMap<String, String>, syntax: AliasSyntax) -> Vec<Vec<String>> {
+    let mut names: Vec<&String> = alias_map.keys().collect();
+    names.sort();
+
+    let mut cycles: Vec<Vec<String>> = Vec::new();
+    for name in names {
+        if cycles.iter().flatten().any(|n| n == name) {
+            continue;
+        }
+        let mut errors: Vec<AliasError> = Vec::new();
+        expand(
+            &alias_map[name],
+            alias_map,
+            syntax,
+            &mut vec![name.to_owned()],
+            &mut errors,
+        );
+        for error in errors {
+            if let AliasError::Cycle(chain) = error {
+                if !cycles.contains(&chain) {
+                    cycles.push(chain);
+                }
+            }
+        }
+    }
+    cycles
+}
+
+/// Replaces the alias `name`, or reports why it can't be. `stack` holds the aliases that are
+/// currently being expanded.
+fn expand_alias(
+    name: &str,
+    alias_map: &HashMap<String, String>,
+    syntax: AliasSyntax,
+    stack: &mut Vec<String>,
+    errors: &mut Vec<AliasError>,
+) -> Option<String> {
+    let replacement = match alias_map.get(name) {
+        Some(v) => v,
+        None => {
+            errors.push(AliasError::Unresolved(name.to_owned()));
+            return None;
+        }
+    };
+    if let Some(position) = stack.iter().position(|n| n == name) {
+        let mut chain: Vec<String> = stack[position..].to_vec();
+        chain.push(name.to_owned());
+        errors.push(AliasError::Cycle(chain));
+        return None;
+    }
+    stack.push(name.to_owned());
+    let result = expand(replacement, alias_map, syntax, stack, errors);
+    stack.pop();
+    Some(result)
+}
+
+/// Replaces every `${ENV:NAME}` with the value of the environment variable `NAME`.
+fn interpolate_environment(path: &str, errors: &mut Vec<AliasError>) -> String {
+    const START: &str = "${ENV:";
+    let mut result = String::new();
+    let mut rest = path;
+
+    while let Some(index) = rest.find(START) {
+        result.push_str(&rest[..index]);
+        let after = &rest[index + START.len()..];
+        match after.find('}') {
+            Some(end) => {
+                let name = &after[..end];
+                match env::var(name) {
+                    Ok(value) => result.push_str(&value),
+                    Err(_) => {
+                        errors.push(AliasError::MissingEnvironmentVariable(name.to_owned()));
+                        result.push_str(&rest[index..index + START.len() + end + 1]);
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push_str(&rest[index..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+fn expand(
+    path: &str,
+    alias_map: &HashMap<String, String>,
+    syntax: AliasSyntax,
+    stack: &mut Vec<String>,
+    errors: &mut Vec<AliasError>,
+) -> String {
+    let path = interpolate_environment(path, errors);
+    let mut result = String::new();
+    let mut rest = path.as_str();
+
+    while let Some(index) = rest.find('@') {
+        result.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+
+        if let Some(after_escape) = after.strip_prefix('@') {
+            result.push('@');
+            rest = after_escape;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => {
+                    let name = &braced[..end];
+                    match expand_alias(name, alias_map, syntax, stack, errors) {
+                        Some(replacement) => result.push_str(&replacement),
+                        None => result.push_str(&rest[index..index + end + 3]),
+                    }
+                    rest = &braced[end + 1..];
+                }
+                None => {
+                    errors.push(AliasError::Unresolved(braced.to_owned()));
+                    result.push_str(&rest[index..]);
+                    rest = "";
+                }
+            }
+        } else if syntax == AliasSyntax::Legacy {
+            // The longest matching name wins, so `@drivers` never turns into `@drive` + `rs`.
+            let longest = alias_map
+                .keys()
+                .filter(|name| !name.is_empty() && after.starts_with(name.as_str()))
+                .max_by_key(|name| name.len());
+            match longest {
+                Some(name) => {
+                    match expand_alias(name, alias_map, syntax, stack, errors) {
+                        Some(replacement) => result.push_str(&replacement),
+                        None => {
+                            result.push('@');
+                            result.push_str(name);
+                        }
+                    }
+                    rest = &after[name.len()..];
+                }
+                None => {
+                    let name: String = after.chars().take_while(|c| is_alias_char(*c)).collect();
+                    errors.push(AliasError::Unresolved(name));
+                    result.push('@');
+                    rest = after;
+                }
+            }
+        } else {
+            result.push('@');
+            rest = after;
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias_map() -> HashMap<String, String> {
+        let mut alias_map = HashMap::new();
+        alias_map.insert(String::from("drive"), String::from("C:"));
+        alias_map.insert(String::from("drivers"), String::from("D:\\drivers"));
+        alias_map
+    }
+
+    #[test]
+    fn test_longest_legacy_alias_wins() {
+        for _ in 0..10 {
+            assert_eq!(
+                (String::from("D:\\drivers\\C:"), vec![]),
+                substitute("@drivers\\@drive", &alias_map(), AliasSyntax::Legacy)
+            );
+        }
+    }
+
+    #[test]
+    fn test_braced_alias() {
+        assert_eq!(
+            (String::from("C:rs\\D:\\drivers"), vec![]),
+            substitute("@{drive}rs\\@{drivers}", &alias_map(), AliasSyntax::Braced)
+        );
+        assert_eq!(
+            (String::from("user@drive"), vec![]),
+            substitute("user@drive", &alias_map(), AliasSyntax::Braced)
+        );
+    }
+
+    #[test]
+    fn test_escaped_at() {
+        assert_eq!(
+            (String::from("user@drive\\C:"), vec![]),
+            substitute("user@@drive\\@drive", &alias_map(), AliasSyntax::Legacy)
+        );
+        assert_eq!(
+            (String::from("@{drive}"), vec![]),
+            substitute("@@{drive}", &alias_map(), AliasSyntax::Braced)
+        );
+    }
+
+    #[test]
+    fn test_unresolved_alias() {
+        assert_eq!(
+            (
+                String::from("@unknown\\@{other}"),
+                vec![
+                    AliasError::Unresolved(String::from("unknown")),
+                    AliasError::Unresolved(String::from("other"))
+                ]
+            ),
+            substitute("@unknown\\@{other}", &alias_map(), AliasSyntax::Legacy)
+        );
+    }
+
+    #[test]
+    fn test_nested_aliases() {
+        let mut alias_map = alias_map();
+        alias_map.insert(String::from("branch"), String::from("main"));
+        alias_map.insert(
+            String::from("workspace"),
+            String::from("@drive\\workspaces\\@{branch}"),
+        );
+        assert_eq!(
+            (String::from("C:\\workspaces\\main\\bin"), vec![]),
+            substitute("@workspace\\bin", &alias_map, AliasSyntax::Legacy)
+        );
+        assert!(find_cycles(&alias_map, AliasSyntax::Legacy).is_empty());
+    }
+
+    #[test]
+    fn test_alias_cycle() {
+        let mut alias_map = alias_map();
+        alias_map.insert(String::from("a"), String::from("@{b}\\a"));
+        alias_map.insert(String::from("b"), String::from("@{a}\\b"));
+        alias_map.insert(String::from("c"), String::from("@{c}"));
+
+        let (_, errors) = substitute("@{a}", &alias_map, AliasSyntax::Braced);
+        assert_eq!(
+            errors,
+            vec![AliasError::Cycle(vec![
+                String::from("a"),
+                String::from("b"),
+                String::from("a")
+            ])]
+        );
+        assert_eq!(
+            find_cycles(&alias_map, AliasSyntax::Braced),
+            vec![
+                vec![String::from("a"), String::from("b"), String::from("a")],
+                vec![String::from("c"), String::from("c")]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_environment_interpolation() {
+        env::set_var("AUTOCOPIER_TEST_USERNAME", "midas");
+        let mut alias_map = alias_map();
+        alias_map.insert(
+            String::from("home"),
+            String::from("@{drive}\\Users\\${ENV:AUTOCOPIER_TEST_USERNAME}"),
+        );
+        assert_eq!(
+            (String::from("C:\\Users\\midas\\bin"), vec![]),
+            substitute("@{home}\\bin", &alias_map, AliasSyntax::Braced)
+        );
+        assert_eq!(
+            (
+                String::from("${ENV:AUTOCOPIER_TEST_UNSET}\\bin"),
+                vec![AliasError::MissingEnvironmentVariable(String::from(
+                    "AUTOCOPIER_TEST_UNSET"
+                ))]
+            ),
+            substitute(
+                "${ENV:AUTOCOPIER_TEST_UNSET}\\bin",
+                &alias_map,
+                AliasSyntax::Braced
+            )
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides = overrides(
+            Some("drive=E:;branch=develop;"),
+            &[String::from("branch=feature=x")],
+        )
+        .unwrap();
+        assert_eq!(overrides.get("drive").unwrap(), "E:");
+        assert_eq!(overrides.get("branch").unwrap(), "feature=x");
+
+        assert!(super::overrides(None, &[String::from("branch")]).is_err());
+        assert!(super::overrides(None, &[String::from("=develop")]).is_err());
+    }
+}
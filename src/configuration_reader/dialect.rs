@@ -0,0 +1,178 @@
+use std::path::MAIN_SEPARATOR;
+
+/// Translates the paths of a configuration, which may be written for another OS, to paths on
+/// this host. Drive letters and UNC prefixes are mapped to mount points, and separators become
+/// the ones of this host.
+#[derive(Debug, Clone, Default)]
+pub struct PathDialect {
+    /// The prefixes with their mount points, longest prefix first.
+    mounts: Vec<(String, String)>,
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || c == '\\'
+}
+
+impl PathDialect {
+    /// `mounts` maps prefixes such as `Z:` or `\\fileserver\builds` to the directory they are
+    /// mounted at, such as `/mnt/share`.
+    pub fn new(mounts: Vec<(String, String)>) -> PathDialect {
+        let mut mounts: Vec<(String, String)> = mounts
+            .into_iter()
+            .map(|(prefix, mount)| (prefix.trim_end_matches(is_separator).to_owned(), mount))
+            .collect();
+        mounts.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        PathDialect { mounts }
+    }
+
+    /// Returns the rest of `path` after `prefix`, if `path` starts with it. Separators are equal
+    /// to each other, and letters are compared case-insensitively, like Windows does.
+    fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+        let mut rest = path.char_indices();
+        for expected in prefix.chars() {
+            let (_, actual) = rest.next()?;
+            let same = (is_separator(expected) && is_separator(actual))
+                || expected.eq_ignore_ascii_case(&actual);
+            if !same {
+                return None;
+            }
+        }
+        let rest = match rest.next() {
+            Some((index, c)) if is_separator(c) => &path[index..],
+            Some(_) => return None,
+            None => "",
+        };
+        Some(rest)
+    }
+
+    /// The drive letter or UNC share `path` starts with, such as `C:` or `\\fileserver\builds`,
+    /// if no mount maps it and it can't be an absolute path on this host.
+    pub fn unmapped_prefix(&self, path: &str) -> Option<String> {
+        if cfg!(windows)
+            || self
+                .mounts
+                .iter()
+                .any(|(prefix, _)| PathDialect::strip_prefix(path, prefix).is_some())
+        {
+            return None;
+        }
+        let mut chars = path.chars();
+        match (chars.next(), chars.next()) {
+            (Some(drive), Some(':')) if drive.is_ascii_alphabetic() => Some(path[..2].to_owned()),
+            (Some(a), Some(b)) if is_separator(a) && is_separator(b) => {
+                let share: Vec<&str> = path
+                    .split(is_separator)
+                    .filter(|c| !c.is_empty())
+                    .take(2)
+                    .collect();
+                Some(format!("\\\\{}", share.join("\\")))
+            }
+            _ => None,
+        }
+    }
+
+    /// Translates `path` to a path on this host.
+    pub fn translate(&self, path: &str) -> String {
+        let mapped = self.mounts.iter().find_map(|(prefix, mount)| {
+            PathDialect::strip_prefix(path, prefix)
+                .map(|rest| format!("{}{}", mount.trim_end_matches(is_separator), rest))
+        });
+        let path = mapped.as_deref().unwrap_or(path);
+
+        // A UNC path keeps the separators it starts with, every other run of separators becomes a
+        // single one.
+        let unc = path.starts_with("\\\\") || path.starts_with("//");
+        let mut result = String::with_capacity(path.len());
+        if unc {
+            result.push(MAIN_SEPARATOR);
+        }
+        let mut previous_was_separator = false;
+        for c in path.chars() {
+            if is_separator(c) {
+                if !previous_was_separator {
+                    result.push(MAIN_SEPARATOR);
+                }
+                previous_was_separator = true;
+            } else {
+                result.push(c);
+                previous_was_separator = false;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `path` with the separators of this host.
+    fn native(path: &str) -> String {
+        path.replace('/', std::path::MAIN_SEPARATOR_STR)
+    }
+
+    #[test]
+    fn test_separators() {
+        let dialect = PathDialect::default();
+        assert_eq!(
+            native("Test/Extra/More/"),
+            dialect.translate("Test\\\\\\Extra\\\\More\\")
+        );
+        assert_eq!(
+            native("C:/bin/app.exe"),
+            dialect.translate("C:\\bin/app.exe")
+        );
+        assert_eq!(
+            native("//fileserver/builds/app.exe"),
+            dialect.translate("\\\\fileserver\\builds\\\\app.exe")
+        );
+    }
+
+    #[test]
+    fn test_mounts() {
+        let dialect = PathDialect::new(vec![
+            (String::from("Z:"), String::from("/mnt/share")),
+            (
+                String::from("\\\\fileserver\\builds\\"),
+                String::from("/mnt/builds/"),
+            ),
+            (
+                String::from("\\\\fileserver\\builds\\nightly"),
+                String::from("/mnt/nightly"),
+            ),
+        ]);
+        assert_eq!(
+            native("/mnt/share/bin/app.exe"),
+            dialect.translate("z:\\bin\\app.exe")
+        );
+        assert_eq!(native("/mnt/share"), dialect.translate("Z:"));
+        assert_eq!(
+            native("/mnt/builds/app.exe"),
+            dialect.translate("\\\\FileServer\\Builds\\app.exe")
+        );
+        // The longest prefix wins.
+        assert_eq!(
+            native("/mnt/nightly/app.exe"),
+            dialect.translate("//fileserver/builds/nightly/app.exe")
+        );
+        // Only whole components match.
+        assert_eq!(
+            native("//fileserver/builds2/app.exe"),
+            dialect.translate("\\\\fileserver\\builds2\\app.exe")
+        );
+        assert_eq!(native("C:/bin"), dialect.translate("C:\\bin"));
+
+        if cfg!(windows) {
+            assert_eq!(None, dialect.unmapped_prefix("C:\\bin"));
+        } else {
+            assert_eq!(Some(String::from("C:")), dialect.unmapped_prefix("C:\\bin"));
+            assert_eq!(
+                Some(String::from("\\\\fileserver\\builds2")),
+                dialect.unmapped_prefix("\\\\fileserver\\builds2\\app.exe")
+            );
+        }
+        assert_eq!(None, dialect.unmapped_prefix("z:\\bin"));
+        assert_eq!(None, dialect.unmapped_prefix("//fileserver/builds/app.exe"));
+        assert_eq!(None, dialect.unmapped_prefix("\\bin\\app.exe"));
+    }
+}
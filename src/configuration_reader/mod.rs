@@ -1,38 +1,324 @@
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::collections::HashMap;
-use std::fs;
+use std::env;
 use std::fs::File;
-use std::io;
 use std::io::Error;
 use std::io::ErrorKind;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use std::time::SystemTime;
-use std::{thread, time};
 
-use crate::FileDescription;
+use crate::content::HashAlgorithm;
+use crate::copy::Backoff;
+use crate::copy::CopyOptions;
+use crate::copy::FileDescription;
+use crate::copy::Group;
+use crate::copy::RemovePolicy;
+use crate::copy::RenamePolicy;
+use crate::copy::RetryPolicy;
+use crate::copy::Settle;
+use crate::rules::DirectoryDescription;
+use crate::rules::GlobDescription;
 use crate::StepInChain;
 
+mod aliases;
+mod dialect;
+mod validation;
+
+pub use aliases::{AliasError, AliasSyntax};
+pub use dialect::PathDialect;
+pub use validation::{Problem, ValidationReport};
+
 #[derive(Debug)]
 pub struct Configuration {
     pub files: Vec<FileDescription>,
+    pub globs: Vec<GlobDescription>,
+    pub directories: Vec<DirectoryDescription>,
+}
+
+/// The formats a configuration file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigurationFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigurationFormat {
+    pub fn from_name(name: &str) -> Option<ConfigurationFormat> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(ConfigurationFormat::Json),
+            "toml" => Some(ConfigurationFormat::Toml),
+            "yaml" | "yml" => Some(ConfigurationFormat::Yaml),
+            _ => None,
+        }
+    }
+
+    /// Detects the format from the extension of the file, falling back to json.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ConfigurationFormat {
+        path.as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(ConfigurationFormat::from_name)
+            .unwrap_or(ConfigurationFormat::Json)
+    }
+
+    fn deserialize(self, contents: &str) -> Result<JsonConfiguration, Error> {
+        // All of the errors below mention the line and column of the problem.
+        let result = match self {
+            ConfigurationFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            ConfigurationFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            ConfigurationFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+        };
+        result.map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid {:?} configuration: {}", self, e),
+            )
+        })
+    }
+}
+
+/// What relative paths in the configuration are relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathResolution {
+    /// The current directory of the process, which is how older configurations behave.
+    #[default]
+    WorkingDirectory,
+    /// The directory the configuration file is in, so it doesn't matter where autocopier is
+    /// started from.
+    Configuration,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonConfiguration {
+    alias_syntax: Option<AliasSyntax>,
+    path_resolution: Option<PathResolution>,
+    /// The mount points of drive letters and UNC prefixes, for configurations shared between
+    /// Windows and other hosts.
+    mounts: Option<Vec<JsonMount>>,
     aliases: Option<Vec<JsonAliases>>,
+    /// Legacy shorthand for the aliases of the `start` step.
     from_aliases: Option<Vec<JsonAliases>>,
+    /// Legacy shorthand for the aliases of the `end` step.
     to_aliases: Option<Vec<JsonAliases>>,
+    steps: Option<Vec<JsonStep>>,
+    /// The retry policy of every file that doesn't set its own.
+    retry: Option<JsonRetryPolicy>,
     files: Vec<JsonFileDescription>,
 }
 
+impl JsonConfiguration {
+    /// What relative paths are relative to. Configurations that use `steps` or the `locations` of
+    /// a file are newer than `path_resolution`, so they default to the configuration file. Older
+    /// ones keep using the current directory, like they always did.
+    fn path_resolution(&self) -> PathResolution {
+        if let Some(v) = self.path_resolution {
+            return v;
+        }
+        let new_schema = self.steps.is_some() || self.files.iter().any(|f| f.locations.is_some());
+        if new_schema {
+            PathResolution::Configuration
+        } else {
+            PathResolution::default()
+        }
+    }
+}
+
+/// A step in the copy chain, which only carries the aliases that apply when running that step.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonStep {
+    name: String,
+    aliases: Option<Vec<JsonAliases>>,
+    /// The directory that relative paths are relative to when running this step. It is relative
+    /// itself according to `path_resolution`.
+    base_dir: Option<String>,
+    /// Mount points that override the global ones with the same prefix when running this step.
+    mounts: Option<Vec<JsonMount>>,
+}
+
+/// Maps a drive letter or UNC prefix, such as `Z:` or `\\fileserver\builds`, to the directory it
+/// is mounted at on this host.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JsonMount {
+    prefix: String,
+    path: String,
+}
+
+/// How often a failing copy is attempted again. Every field that isn't set falls back to the
+/// global policy, and then to the default.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct JsonRetryPolicy {
+    max_attempts: Option<u32>,
+    backoff: Option<Backoff>,
+    delay_ms: Option<u64>,
+    max_delay_ms: Option<u64>,
+    timeout_ms: Option<u64>,
+}
+
+impl JsonRetryPolicy {
+    fn resolve(&self, global: &JsonRetryPolicy) -> Result<RetryPolicy, String> {
+        let default = RetryPolicy::default();
+        let max_attempts = self
+            .max_attempts
+            .or(global.max_attempts)
+            .unwrap_or(default.max_attempts);
+        if max_attempts == 0 {
+            return Err(String::from("retry max_attempts should be at least 1"));
+        }
+        let milliseconds =
+            |own: Option<u64>, global: Option<u64>| own.or(global).map(Duration::from_millis);
+        Ok(RetryPolicy {
+            max_attempts,
+            backoff: self.backoff.or(global.backoff).unwrap_or(default.backoff),
+            delay: milliseconds(self.delay_ms, global.delay_ms).unwrap_or(default.delay),
+            max_delay: milliseconds(self.max_delay_ms, global.max_delay_ms)
+                .unwrap_or(default.max_delay),
+            timeout: milliseconds(self.timeout_ms, global.timeout_ms).or(default.timeout),
+        })
+    }
+}
+
+/// When a changed source is copied, see `Settle`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum JsonSettle {
+    Immediately,
+    Stable { duration_ms: u64 },
+    ExclusiveOpen,
+}
+
+impl JsonSettle {
+    fn resolve(&self) -> Settle {
+        match self {
+            JsonSettle::Immediately => Settle::Immediately,
+            JsonSettle::Stable { duration_ms } => {
+                Settle::Stable(Duration::from_millis(*duration_ms))
+            }
+            JsonSettle::ExclusiveOpen => Settle::ExclusiveOpen,
+        }
+    }
+}
+
+/// What happens to the destination when its source is removed, see `RemovePolicy`. The
+/// quarantine directory can use aliases.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum JsonRemovePolicy {
+    Keep,
+    Delete,
+    Quarantine { directory: String },
+}
+
+/// What the paths of a file description point to.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    #[default]
+    File,
+    /// The whole directory tree is mirrored.
+    Directory,
+}
+
+/// A file, either described by the legacy `from`/`through`/`to` triple or by an ordered list of
+/// named locations.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JsonFileDescription {
-    from: String,
-    through: String,
-    to: String,
+    from: Option<String>,
+    through: Option<String>,
+    to: Option<String>,
+    locations: Option<Vec<JsonLocation>>,
+    kind: Option<RuleKind>,
+    /// Only for directories: patterns, relative to the directory, of the files to mirror.
+    include: Option<Vec<String>>,
+    /// Only for directories: patterns, relative to the directory, of the files not to mirror.
+    exclude: Option<Vec<String>>,
+    /// Only for directories: delete the files in the destination that aren't in the source.
+    delete_extraneous: Option<bool>,
+    /// Copy through a temporary file that is renamed over the destination. Defaults to true.
+    atomic: Option<bool>,
+    retry: Option<JsonRetryPolicy>,
+    /// Wait for the source to be completely written before copying it.
+    settle: Option<JsonSettle>,
+    /// Don't copy when the destination already has the same content. Defaults to true.
+    skip_unchanged: Option<bool>,
+    /// Compare the checksums of the source and the copy.
+    verify: Option<HashAlgorithm>,
+    /// Write a checksum file next to the copies in the locations in between, and check it before
+    /// copying them onwards.
+    checksums: Option<bool>,
+    /// Write a handoff marker next to the copies in the locations in between, and only copy
+    /// them onwards once their marker says they are complete.
+    handoff: Option<bool>,
+    /// Copy the files of all entries with the same group together. The files an entry expands to
+    /// with braces form a group of their own, unless the entry names one.
+    group: Option<String>,
+    /// Defaults to keeping the destination, or deleting it for directories.
+    on_remove: Option<JsonRemovePolicy>,
+    /// Defaults to copying the renamed file, or renaming the destination for directories.
+    on_rename: Option<RenamePolicy>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JsonLocation {
+    name: String,
+    path: String,
+}
+
+impl JsonFileDescription {
+    /// Returns the locations of this file in chain order. The legacy triple is turned into the
+    /// locations `from`, `through` and `to`.
+    fn locations(&self) -> Result<Vec<JsonLocation>, Error> {
+        if let Some(locations) = &self.locations {
+            if locations.len() < 2 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "A file needs at least two locations",
+                ));
+            }
+            return Ok(locations.clone());
+        }
+        match (&self.from, &self.through, &self.to) {
+            (Some(from), Some(through), Some(to)) => Ok(vec![
+                JsonLocation {
+                    name: String::from("from"),
+                    path: from.to_owned(),
+                },
+                JsonLocation {
+                    name: String::from("through"),
+                    path: through.to_owned(),
+                },
+                JsonLocation {
+                    name: String::from("to"),
+                    path: to.to_owned(),
+                },
+            ]),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "A file needs either 'locations' or all of 'from', 'through' and 'to'",
+            )),
+        }
+    }
+
+    /// Returns the index of the location this step copies from, if this file takes part in it.
+    fn hop(locations: &[JsonLocation], step_in_chain: &StepInChain) -> Option<usize> {
+        match step_in_chain {
+            StepInChain::Start => Some(0),
+            StepInChain::End => Some(locations.len() - 2),
+            StepInChain::Named(name) => locations
+                .iter()
+                .position(|l| &l.name == name)
+                .filter(|i| i + 1 < locations.len()),
+        }
+    }
+
+    fn has_location(&self, name: &str) -> bool {
+        match self.locations() {
+            Ok(locations) => locations.iter().any(|l| l.name == name),
+            Err(_) => false,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,12 +327,43 @@ pub struct JsonAliases {
     replacement: String,
 }
 
-fn simplify(to_simplify: &str) -> String {
-    let mut s = String::from(to_simplify.clone());
-    while (s.contains("\\\\")) {
-        s = s.replace("\\\\", "\\");
+/// Whether `path` is absolute on any platform, so a configuration written for Windows is read the
+/// same everywhere.
+fn is_absolute(path: &str) -> bool {
+    let mut chars = path.chars();
+    match (chars.next(), chars.next()) {
+        (Some('/'), _) | (Some('\\'), _) => true,
+        (Some(drive), Some(':')) => drive.is_ascii_alphabetic(),
+        _ => false,
     }
-    s
+}
+
+/// Makes the relative `path` relative to `base` instead, if there is one.
+fn resolve_relative(path: &str, base: Option<&Path>) -> String {
+    match base {
+        Some(base) if !is_absolute(path) => base.join(path).to_string_lossy().into_owned(),
+        _ => path.to_owned(),
+    }
+}
+
+/// A source with wildcards, or one that is the destination template of an earlier hop, copies
+/// every file that matches it.
+fn is_glob(path: &str) -> bool {
+    path.contains('*')
+        || path.contains('?')
+        || path.contains(GlobDescription::RELPATH)
+        || path.contains(GlobDescription::FILENAME)
+}
+
+/// Turns the placeholders of a destination template back into wildcards, so the template of one
+/// hop can be the source of the next one.
+fn template_to_pattern(template: &str) -> String {
+    template
+        .replace(
+            GlobDescription::RELPATH,
+            &format!("**{}*", std::path::MAIN_SEPARATOR),
+        )
+        .replace(GlobDescription::FILENAME, "*")
 }
 
 fn contains_multiple(potential_multiple: &str) -> bool {
@@ -79,110 +396,378 @@ fn extract_multiple(multiples: &str) -> Vec<String> {
     result_vector
 }
 
+/// Parses a configuration, which is in `directory` if it was read from a file.
+///
+/// Aliases are looked up in this order, where later ones override earlier ones: the global
+/// `aliases`, the aliases of the step, and finally `alias_overrides`, which come from the
+/// environment and the command line.
 fn parse_configuration_from_string(
-    json_string: &str,
-    step_in_chain: StepInChain,
-) -> Result<(Configuration, Vec<FileDescription>), Error> {
-    let json_configuration: JsonConfiguration = serde_json::from_str(&json_string)?;
+    contents: &str,
+    format: ConfigurationFormat,
+    step_in_chain: &StepInChain,
+    alias_overrides: &HashMap<String, String>,
+    directory: Option<&Path>,
+) -> Result<Configuration, Error> {
+    let json_configuration: JsonConfiguration = format.deserialize(contents)?;
+
+    // What the relative paths of this step are relative to, if not the current directory.
+    let relative_to = match json_configuration.path_resolution() {
+        PathResolution::WorkingDirectory => None,
+        PathResolution::Configuration => directory,
+    };
+    let step = json_configuration
+        .steps
+        .iter()
+        .flatten()
+        .find(|step| step.name == step_in_chain.name());
+
+    // The mounts of the step override the global ones with the same prefix.
+    let mut mounts: Vec<(String, String)> = Vec::new();
+    let step_mounts = step.and_then(|step| step.mounts.as_ref());
+    for mount in json_configuration
+        .mounts
+        .iter()
+        .chain(step_mounts)
+        .flatten()
+    {
+        mounts.retain(|(prefix, _)| !prefix.eq_ignore_ascii_case(&mount.prefix));
+        mounts.push((mount.prefix.to_owned(), mount.path.to_owned()));
+    }
+    let dialect = PathDialect::new(mounts);
 
-    let mut configuration: Configuration = Configuration { files: Vec::new() };
+    let base: Option<PathBuf> = match step.and_then(|step| step.base_dir.as_deref()) {
+        Some(base_dir) => Some(PathBuf::from(resolve_relative(
+            &dialect.translate(base_dir),
+            relative_to,
+        ))),
+        None => relative_to.map(Path::to_path_buf),
+    };
 
-    let mut failed_vec: Vec<FileDescription> = Vec::new();
+    let mut configuration: Configuration = Configuration {
+        files: Vec::new(),
+        globs: Vec::new(),
+        directories: Vec::new(),
+    };
+
+    let mut problems: Vec<Problem> = Vec::new();
+    let alias_syntax = json_configuration.alias_syntax.unwrap_or_default();
+    let global_retry = json_configuration.retry.clone().unwrap_or_default();
     let mut alias_map: HashMap<String, String> = HashMap::new();
 
-    if json_configuration.aliases.is_some() {
-        json_configuration
-            .aliases
-            .unwrap()
-            .iter()
-            .for_each(|alias| {
-                alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
-            });
+    if let Some(aliases) = json_configuration.aliases {
+        aliases.iter().for_each(|alias| {
+            alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
+        });
     }
 
     // Insert more aliases based on step in copy chain.
     match step_in_chain {
         StepInChain::Start => {
-            if json_configuration.from_aliases.is_some() {
-                json_configuration
-                    .from_aliases
-                    .unwrap()
-                    .iter()
-                    .for_each(|alias| {
-                        alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
-                    });
+            if let Some(aliases) = json_configuration.from_aliases {
+                aliases.iter().for_each(|alias| {
+                    alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
+                });
             }
         }
         StepInChain::End => {
-            if json_configuration.to_aliases.is_some() {
-                json_configuration
-                    .to_aliases
-                    .unwrap()
+            if let Some(aliases) = json_configuration.to_aliases {
+                aliases.iter().for_each(|alias| {
+                    alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
+                });
+            }
+        }
+        StepInChain::Named(name) => {
+            let is_known = json_configuration
+                .steps
+                .iter()
+                .flatten()
+                .any(|step| &step.name == name)
+                || json_configuration
+                    .files
                     .iter()
-                    .for_each(|alias| {
-                        alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
-                    });
+                    .any(|f| f.has_location(name));
+            if !is_known {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Step in chain was not recognised, got {}", name),
+                ));
             }
         }
     }
 
-    json_configuration.files.iter().for_each(|f| {
-        let mut from: String;
-        let mut to: String;
-        match step_in_chain {
-            StepInChain::Start => {
-                from = f.from.to_owned();
-                to = f.through.to_owned();
+    // The aliases of the step itself override everything else.
+    if let Some(steps) = &json_configuration.steps {
+        steps
+            .iter()
+            .filter(|step| step.name == step_in_chain.name())
+            .filter_map(|step| step.aliases.as_ref())
+            .flatten()
+            .for_each(|alias| {
+                alias_map.insert(alias.name.to_owned(), alias.replacement.to_owned());
+            });
+    }
+
+    alias_map.extend(
+        alias_overrides
+            .iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned())),
+    );
+
+    for chain in aliases::find_cycles(&alias_map, alias_syntax) {
+        problems.push(Problem::AliasCycle { chain });
+    }
+
+    for (index, f) in json_configuration.files.iter().enumerate() {
+        let file = index + 1;
+        let locations = match f.locations() {
+            Ok(v) => v,
+            Err(e) => {
+                problems.push(Problem::InvalidFile {
+                    file,
+                    message: e.to_string(),
+                });
+                continue;
             }
-            StepInChain::End => {
-                from = f.through.to_owned();
-                to = f.to.to_owned();
+        };
+        let hop = match JsonFileDescription::hop(&locations, step_in_chain) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let substituted: Vec<(String, Vec<AliasError>)> = locations
+            .iter()
+            .map(|l| aliases::substitute(&l.path, &alias_map, alias_syntax))
+            .collect();
+        let paths: Vec<String> = substituted.iter().map(|(p, _)| p.to_owned()).collect();
+        let from = resolve_relative(&dialect.translate(&paths[hop]), base.as_deref());
+        let to = resolve_relative(&dialect.translate(&paths[hop + 1]), base.as_deref());
+        let quarantine = match &f.on_remove {
+            Some(JsonRemovePolicy::Quarantine { directory }) => {
+                let (directory, errors) = aliases::substitute(directory, &alias_map, alias_syntax);
+                let translated = dialect.translate(&directory);
+                Some((
+                    resolve_relative(&translated, base.as_deref()),
+                    errors,
+                    directory,
+                ))
             }
-        }
+            _ => None,
+        };
 
-        alias_map.iter().for_each(|am| {
-            let alias: &str = &("@".to_owned() + am.0);
-            let replacement: &str = am.1;
-            from = from.replace(alias, replacement);
-            to = to.replace(alias, replacement);
+        // Only the hop this step runs has to be resolvable with the aliases of this step.
+        let problem_count = problems.len();
+        let quarantine_path = quarantine.as_ref().map(|(_, _, path)| path);
+        for path in paths[hop..hop + 2].iter().chain(quarantine_path) {
+            if let Some(prefix) = dialect.unmapped_prefix(path) {
+                problems.push(Problem::UnmappedPrefix {
+                    file,
+                    path: path.to_owned(),
+                    prefix,
+                });
+            }
+        }
+        let quarantine = quarantine.map(|(directory, errors, _)| (directory, errors));
+        for (path, errors) in substituted[hop..hop + 2].iter().chain(&quarantine) {
+            for error in errors {
+                match error {
+                    AliasError::Unresolved(alias) => problems.push(Problem::UnresolvedAlias {
+                        file,
+                        path: path.to_owned(),
+                        alias: alias.to_owned(),
+                    }),
+                    AliasError::MissingEnvironmentVariable(variable) => {
+                        problems.push(Problem::MissingEnvironmentVariable {
+                            file,
+                            path: path.to_owned(),
+                            variable: variable.to_owned(),
+                        })
+                    }
+                    // Cycles are reported once for the whole configuration.
+                    AliasError::Cycle(_) => {}
+                }
+            }
+        }
+        let kind = f.kind.unwrap_or_default();
+        let on_remove = match (&f.on_remove, quarantine) {
+            (Some(JsonRemovePolicy::Keep), _) => RemovePolicy::Keep,
+            (Some(JsonRemovePolicy::Delete), _) => RemovePolicy::Delete,
+            (_, Some((directory, _))) => RemovePolicy::Quarantine(PathBuf::from(directory)),
+            (None, _) if kind == RuleKind::Directory => RemovePolicy::Delete,
+            _ => RemovePolicy::Keep,
+        };
+        let on_rename = f.on_rename.unwrap_or(match kind {
+            RuleKind::File => RenamePolicy::Copy,
+            RuleKind::Directory => RenamePolicy::Rename,
         });
-        // Check if multiple subsets are in there
+        let retry = match f.retry.clone().unwrap_or_default().resolve(&global_retry) {
+            Ok(v) => v,
+            Err(message) => {
+                problems.push(Problem::InvalidFile { file, message });
+                continue;
+            }
+        };
+        let checksums = f.checksums.unwrap_or(false);
+        let handoff = f.handoff.unwrap_or(false);
+        let options = CopyOptions {
+            atomic: f.atomic.unwrap_or(true),
+            retry,
+            settle: f
+                .settle
+                .as_ref()
+                .map(JsonSettle::resolve)
+                .unwrap_or_default(),
+            skip_unchanged: f.skip_unchanged.unwrap_or(true),
+            verify: f.verify,
+            write_checksum: checksums && hop + 2 < locations.len(),
+            check_checksum: checksums && hop > 0,
+            write_handoff: handoff && hop + 2 < locations.len(),
+            check_handoff: handoff && hop > 0,
+            group: f.group.clone().map(Group::Named),
+            on_remove,
+            on_rename,
+        };
+        if kind == RuleKind::File && is_glob(&from) {
+            // The brace groups of a pattern are alternatives of the pattern itself, so they don't
+            // have to match up with the destination template.
+            let pattern = template_to_pattern(&from);
+            validation::check_braces(file, std::slice::from_ref(&pattern), &mut problems);
+            if !to.contains(GlobDescription::RELPATH) && !to.contains(GlobDescription::FILENAME) {
+                problems.push(Problem::MissingPlaceholder {
+                    file,
+                    path: to.to_owned(),
+                });
+            }
+            if problems.len() > problem_count {
+                continue;
+            }
+
+            for p in extract_multiple(&pattern) {
+                match GlobDescription::new(&p, &to) {
+                    Ok(glob) => configuration.globs.push(GlobDescription {
+                        options: options.clone(),
+                        ..glob
+                    }),
+                    Err(e) => problems.push(Problem::InvalidPattern {
+                        file,
+                        path: p,
+                        message: e.msg.to_owned(),
+                    }),
+                }
+            }
+            continue;
+        }
+
+        validation::check_braces(file, &paths, &mut problems);
+        if problems.len() > problem_count {
+            continue;
+        }
 
-        if (contains_multiple(&from)) {
+        // Check if multiple subsets are in there
+        let pairs: Vec<(PathBuf, PathBuf)> = if contains_multiple(&from) {
             let multiple_from = extract_multiple(&from);
             let multiple_to = extract_multiple(&to);
 
-            for (f, t) in multiple_from.iter().zip(multiple_to.iter()) {
-                configuration.files.push(FileDescription {
-                    from: PathBuf::from(simplify(f)),
-                    to: PathBuf::from(simplify(t)),
-                });
-            }
+            multiple_from
+                .iter()
+                .zip(multiple_to.iter())
+                .map(|(f, t)| (PathBuf::from(f), PathBuf::from(t)))
+                .collect()
         } else {
-            configuration.files.push(FileDescription {
-                from: PathBuf::from(simplify(&from)),
-                to: PathBuf::from(simplify(&to)),
-            });
+            vec![(PathBuf::from(&from), PathBuf::from(&to))]
+        };
+
+        match kind {
+            RuleKind::File => {
+                let mut options = options;
+                if pairs.len() > 1 && options.group.is_none() {
+                    options.group = Some(Group::Entry(file));
+                }
+                for (from, to) in pairs {
+                    configuration.files.push(FileDescription {
+                        from,
+                        to,
+                        options: options.clone(),
+                    });
+                }
+            }
+            RuleKind::Directory => {
+                let include = validation::compile_patterns(file, &f.include, &mut problems);
+                let exclude = validation::compile_patterns(file, &f.exclude, &mut problems);
+                for (from, to) in pairs {
+                    configuration.directories.push(DirectoryDescription {
+                        from,
+                        to,
+                        include: include.clone(),
+                        exclude: exclude.clone(),
+                        delete_extraneous: f.delete_extraneous.unwrap_or(false),
+                        options: options.clone(),
+                    });
+                }
+            }
         }
-    });
+    }
 
-    Ok((configuration, failed_vec))
+    validation::check_duplicates(
+        &configuration.files,
+        &configuration.directories,
+        &mut problems,
+    );
+    if !problems.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            ValidationReport { problems },
+        ));
+    }
+
+    Ok(configuration)
 }
 
+/// Parses the configuration file at `path`. If no format is given, it is detected from the
+/// extension of the file. `cli_aliases` are `name=value` pairs that override every other alias.
 pub fn parse_configuration(
     path: &str,
-    step_in_chain: StepInChain,
-) -> Result<(Configuration, Vec<FileDescription>), Error> {
+    format: Option<ConfigurationFormat>,
+    step_in_chain: &StepInChain,
+    cli_aliases: &[String],
+) -> Result<Configuration, Error> {
     let mut file = File::open(path)?;
     let mut contents: String = String::new();
     file.read_to_string(&mut contents)?;
-    parse_configuration_from_string(&contents, step_in_chain)
+    let format = format.unwrap_or_else(|| ConfigurationFormat::from_path(path));
+    let environment_aliases = env::var(aliases::ENVIRONMENT_ALIASES).ok();
+    let alias_overrides = aliases::overrides(environment_aliases.as_deref(), cli_aliases)?;
+    let directory = env::current_dir()?
+        .join(path)
+        .parent()
+        .map(Path::to_path_buf);
+    parse_configuration_from_string(
+        &contents,
+        format,
+        step_in_chain,
+        &alias_overrides,
+        directory.as_deref(),
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Writes `path` with the separators of this host, like the configuration is translated to.
+    fn native(path: &str) -> PathBuf {
+        PathBuf::from(path.replace(['\\', '/'], std::path::MAIN_SEPARATOR_STR))
+    }
+
+    /// The problems `result` was rejected for.
+    fn problems(result: Result<Configuration, Error>) -> Vec<Problem> {
+        let error = result.unwrap_err();
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ValidationReport>())
+            .map(|report| report.problems.clone())
+            .unwrap_or_default()
+    }
+
     #[test]
     fn test_global_aliases() {
         let parse_result = parse_configuration_from_string(
@@ -201,18 +786,21 @@ mod tests {
                     }
                 ]
             }"#,
-            StepInChain::Start,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
         );
         assert!(parse_result.is_ok());
-        let (configuration, unparsed) = parse_result.unwrap();
+        let configuration = parse_result.unwrap();
         assert_eq!(configuration.files.len(), 1);
         assert_eq!(
-            PathBuf::from(".\\autocopier\\configuration.json"),
-            configuration.files.get(0).unwrap().from
+            native(".\\autocopier\\configuration.json"),
+            configuration.files.first().unwrap().from
         );
         assert_eq!(
-            PathBuf::from(".\\autocopier\\configuration_copy_middle.json"),
-            configuration.files.get(0).unwrap().to
+            native(".\\autocopier\\configuration_copy_middle.json"),
+            configuration.files.first().unwrap().to
         );
     }
 
@@ -243,19 +831,22 @@ mod tests {
                     }
                 ]
             }"#,
-            StepInChain::Start,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
         );
         assert!(parse_result.is_ok());
-        let (configuration, unparsed) = parse_result.unwrap();
+        let configuration = parse_result.unwrap();
         assert_eq!(configuration.files.len(), 1);
         assert_eq!(
-            PathBuf::from(".\\autocopier\\configuration.json"),
-            configuration.files.get(0).unwrap().from
+            native(".\\autocopier\\configuration.json"),
+            configuration.files.first().unwrap().from
         );
         // Assert the to (which is through in the start )
         assert_eq!(
-            PathBuf::from(".\\autocopier\\configuration_copy_middle.json"),
-            configuration.files.get(0).unwrap().to
+            native(".\\autocopier\\configuration_copy_middle.json"),
+            configuration.files.first().unwrap().to
         );
     }
 
@@ -283,18 +874,21 @@ mod tests {
                     }
                 ]
             }"#,
-            StepInChain::End,
+            ConfigurationFormat::Json,
+            &StepInChain::End,
+            &HashMap::new(),
+            None,
         );
         assert!(parse_result.is_ok());
-        let (configuration, unparsed) = parse_result.unwrap();
+        let configuration = parse_result.unwrap();
         assert_eq!(configuration.files.len(), 1);
         assert_eq!(
-            PathBuf::from(".\\tocopier\\configuration_copy_middle.json"),
-            configuration.files.get(0).unwrap().from
+            native(".\\tocopier\\configuration_copy_middle.json"),
+            configuration.files.first().unwrap().from
         );
         assert_eq!(
-            PathBuf::from(".\\tocopier\\configuration_copy.json"),
-            configuration.files.get(0).unwrap().to
+            native(".\\tocopier\\configuration_copy.json"),
+            configuration.files.first().unwrap().to
         );
     }
 
@@ -310,41 +904,833 @@ mod tests {
                     }
                 ]
             }"#,
-            StepInChain::Start,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
         );
         assert!(parse_result.is_ok());
-        let (configuration, unparsed) = parse_result.unwrap();
+        let configuration = parse_result.unwrap();
         assert_eq!(configuration.files.len(), 2);
         assert_eq!(
-            PathBuf::from("\\test\\executable.exe"),
-            configuration.files.get(0).unwrap().from
+            native("\\test\\executable.exe"),
+            configuration.files.first().unwrap().from
         );
         assert_eq!(
-            PathBuf::from("\\othertest\\executable.exe"),
-            configuration.files.get(0).unwrap().to
+            native("\\othertest\\executable.exe"),
+            configuration.files.first().unwrap().to
         );
         assert_eq!(
-            PathBuf::from("\\test\\executable.pdb"),
+            native("\\test\\executable.pdb"),
             configuration.files.get(1).unwrap().from
         );
         assert_eq!(
-            PathBuf::from("\\othertest\\executable.pdb"),
+            native("\\othertest\\executable.pdb"),
             configuration.files.get(1).unwrap().to
         );
     }
 
     #[test]
-    fn test_simplify() {
-        let start_string = "Test\\\\\\Extra\\\\More\\";
-        let result = simplify(start_string);
-        let expected = "Test\\Extra\\More\\";
-        assert_eq!(expected, &result);
+    fn test_named_steps() {
+        let configuration_string = r#"{
+                "mounts": [
+                    { "prefix": "C:", "path": "/c" },
+                    { "prefix": "S:", "path": "/s" }
+                ],
+                "aliases": [
+                    {
+                        "name": "share",
+                        "replacement": "\\build-share"
+                    }
+                ],
+                "steps": [
+                    {
+                        "name": "staging",
+                        "aliases": [
+                            {
+                                "name": "share",
+                                "replacement": "S:"
+                            }
+                        ]
+                    }
+                ],
+                "files": [
+                    {
+                        "locations": [
+                            { "name": "dev", "path": "C:\\dev\\app.exe" },
+                            { "name": "build", "path": "@share\\build\\app.exe" },
+                            { "name": "staging", "path": "@share\\staging\\app.exe" },
+                            { "name": "rig", "path": "C:\\rig\\app.exe" }
+                        ]
+                    }
+                ]
+            }"#;
+
+        let configuration = parse_configuration_from_string(
+            configuration_string,
+            ConfigurationFormat::Json,
+            &StepInChain::Named(String::from("build")),
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(configuration.files.len(), 1);
+        assert_eq!(
+            native("\\build-share\\build\\app.exe"),
+            configuration.files.first().unwrap().from
+        );
+        assert_eq!(
+            native("\\build-share\\staging\\app.exe"),
+            configuration.files.first().unwrap().to
+        );
+
+        let configuration = parse_configuration_from_string(
+            configuration_string,
+            ConfigurationFormat::Json,
+            &StepInChain::Named(String::from("staging")),
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            native("/s/staging/app.exe"),
+            configuration.files.first().unwrap().from
+        );
+        assert_eq!(
+            native("/c/rig/app.exe"),
+            configuration.files.first().unwrap().to
+        );
+
+        let configuration = parse_configuration_from_string(
+            configuration_string,
+            ConfigurationFormat::Json,
+            &StepInChain::End,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            native("/c/rig/app.exe"),
+            configuration.files.first().unwrap().to
+        );
+
+        // The last location has no hop of its own.
+        let configuration = parse_configuration_from_string(
+            configuration_string,
+            ConfigurationFormat::Json,
+            &StepInChain::Named(String::from("rig")),
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(configuration.files.len(), 0);
+
+        assert!(parse_configuration_from_string(
+            configuration_string,
+            ConfigurationFormat::Json,
+            &StepInChain::Named(String::from("unknown")),
+            &HashMap::new(),
+            None,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_incomplete_file_description() {
+        let parse_result = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "from": "\\test\\executable.exe",
+                        "to": "\\moreothertest\\executable.exe"
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        );
+        assert!(parse_result.is_err());
+    }
+
+    #[test]
+    fn test_toml_configuration() {
+        let parse_result = parse_configuration_from_string(
+            r#"
+                # Comments explain why a file is copied.
+                [[aliases]]
+                name = "project-name"
+                replacement = "autocopier"
+
+                [[files]]
+                from = '.\@project-name\configuration.json'
+                through = '.\@project-name\configuration_copy_middle.json'
+                to = '.\@project-name\configuration_copy.json'
+            "#,
+            ConfigurationFormat::Toml,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        );
+        let configuration = parse_result.unwrap();
+        assert_eq!(configuration.files.len(), 1);
+        assert_eq!(
+            native(".\\autocopier\\configuration.json"),
+            configuration.files.first().unwrap().from
+        );
+    }
+
+    #[test]
+    fn test_yaml_configuration() {
+        let parse_result = parse_configuration_from_string(
+            r#"
+# Comments explain why a file is copied.
+aliases:
+  - name: project-name
+    replacement: autocopier
+files:
+  - from: '.\@project-name\configuration.json'
+    through: '.\@project-name\configuration_copy_middle.json'
+    to: '.\@project-name\configuration_copy.json'
+"#,
+            ConfigurationFormat::Yaml,
+            &StepInChain::End,
+            &HashMap::new(),
+            None,
+        );
+        let configuration = parse_result.unwrap();
+        assert_eq!(configuration.files.len(), 1);
+        assert_eq!(
+            native(".\\autocopier\\configuration_copy.json"),
+            configuration.files.first().unwrap().to
+        );
+    }
+
+    #[test]
+    fn test_parse_error_mentions_format_and_location() {
+        let error = parse_configuration_from_string(
+            "files = [\n  { from = 'a' \n",
+            ConfigurationFormat::Toml,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("Toml"));
+        assert!(message.contains("line"));
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            ConfigurationFormat::Json,
+            ConfigurationFormat::from_path("configuration.json")
+        );
+        assert_eq!(
+            ConfigurationFormat::Toml,
+            ConfigurationFormat::from_path("configuration.TOML")
+        );
+        assert_eq!(
+            ConfigurationFormat::Yaml,
+            ConfigurationFormat::from_path("configuration.yml")
+        );
+        assert_eq!(
+            ConfigurationFormat::Json,
+            ConfigurationFormat::from_path("configuration")
+        );
+    }
+
+    #[test]
+    fn test_validation_reports_every_problem() {
+        let error = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "from": "\\test\\@unknown\\executable.exe",
+                        "through": "\\othertest\\executable.exe",
+                        "to": "\\moreothertest\\executable.exe"
+                    },
+                    {
+                        "from": "\\test\\executable.{exe,pdb}",
+                        "through": "\\othertest\\executable.exe",
+                        "to": "\\moreothertest\\executable.{exe,pdb}"
+                    },
+                    {
+                        "from": "\\test\\executable.{exe,}",
+                        "through": "\\othertest\\executable.{exe,}",
+                        "to": "\\moreothertest\\executable.{exe,}"
+                    },
+                    {
+                        "from": "\\test\\library.dll",
+                        "through": "\\othertest\\library.dll",
+                        "to": "\\moreothertest\\library.dll"
+                    },
+                    {
+                        "from": "\\test\\library.dll",
+                        "through": "\\othertest\\other_library.dll",
+                        "to": "\\moreothertest\\other_library.dll"
+                    },
+                    {
+                        "from": "\\test\\copy_of_library.dll",
+                        "through": "\\othertest\\library.dll",
+                        "to": "\\moreothertest\\library.dll"
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap_err();
+        let report = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ValidationReport>())
+            .unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::UnresolvedAlias {
+                    file: 1,
+                    path: String::from("\\test\\@unknown\\executable.exe"),
+                    alias: String::from("unknown"),
+                },
+                Problem::BraceMismatch {
+                    file: 2,
+                    path: String::from("\\test\\executable.{exe,pdb}"),
+                    other_path: String::from("\\othertest\\executable.exe"),
+                },
+                Problem::EmptyAlternative {
+                    file: 3,
+                    path: String::from("\\test\\executable.{exe,}"),
+                },
+                Problem::EmptyAlternative {
+                    file: 3,
+                    path: String::from("\\othertest\\executable.{exe,}"),
+                },
+                Problem::EmptyAlternative {
+                    file: 3,
+                    path: String::from("\\moreothertest\\executable.{exe,}"),
+                },
+                Problem::DuplicateDestination {
+                    destination: native("\\othertest\\library.dll"),
+                    sources: vec![
+                        native("\\test\\library.dll"),
+                        native("\\test\\copy_of_library.dll"),
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_alias_precedence() {
+        let configuration_string = r#"{
+                "aliases": [
+                    { "name": "global", "replacement": "global" },
+                    { "name": "step", "replacement": "global" },
+                    { "name": "override", "replacement": "global" }
+                ],
+                "from_aliases": [
+                    { "name": "step", "replacement": "step" },
+                    { "name": "override", "replacement": "step" }
+                ],
+                "files": [
+                    {
+                        "from": "@{global}\\@{step}\\@{override}",
+                        "through": "\\othertest\\executable.exe",
+                        "to": "\\moreothertest\\executable.exe"
+                    }
+                ]
+            }"#;
+        let mut alias_overrides = HashMap::new();
+        alias_overrides.insert(String::from("override"), String::from("override"));
+        let configuration = parse_configuration_from_string(
+            configuration_string,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &alias_overrides,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            native("global\\step\\override"),
+            configuration.files.first().unwrap().from
+        );
+    }
+
+    #[test]
+    fn test_glob() {
+        let configuration = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "from": "/build/bin/**/*.{dll,pdb}",
+                        "through": "/share/{relpath}",
+                        "to": "/rig/{relpath}"
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(configuration.files.len(), 0);
+        assert_eq!(configuration.globs.len(), 2);
+        assert_eq!(PathBuf::from("/build/bin"), configuration.globs[0].base);
+        assert_eq!(
+            "/build/bin/**/*.dll",
+            configuration.globs[0].pattern.as_str()
+        );
+        assert_eq!(
+            "/build/bin/**/*.pdb",
+            configuration.globs[1].pattern.as_str()
+        );
+        assert_eq!("/share/{relpath}", configuration.globs[0].to);
+
+        // The template of the previous hop is the pattern of the next one.
+        let configuration = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "from": "/build/bin/**/*.{dll,pdb}",
+                        "through": "/share/{relpath}",
+                        "to": "/rig"
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::End,
+            &HashMap::new(),
+            None,
+        );
+        let error = configuration.unwrap_err();
+        let report = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<ValidationReport>())
+            .unwrap();
+        assert_eq!(
+            report.problems,
+            vec![Problem::MissingPlaceholder {
+                file: 1,
+                path: String::from("/rig"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_directory() {
+        let configuration = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "kind": "directory",
+                        "from": "/build/bin",
+                        "through": "/share/bin",
+                        "to": "/rig/bin",
+                        "exclude": ["*.pdb"],
+                        "delete_extraneous": true,
+                        "atomic": false,
+                        "skip_unchanged": false
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(configuration.files.len(), 0);
+        assert_eq!(configuration.directories.len(), 1);
+        let directory = configuration.directories.first().unwrap();
+        assert_eq!(PathBuf::from("/build/bin"), directory.from);
+        assert_eq!(PathBuf::from("/share/bin"), directory.to);
+        assert!(directory.include.is_empty());
+        assert_eq!("*.pdb", directory.exclude[0].as_str());
+        assert!(directory.delete_extraneous);
+        assert!(!directory.options.atomic);
+        assert!(!directory.options.skip_unchanged);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let configuration = parse_configuration_from_string(
+            r#"{
+                "retry": { "max_attempts": 5, "backoff": "exponential", "delay_ms": 50 },
+                "files": [
+                    { "from": "/build/a.exe", "through": "/share/a.exe", "to": "/rig/a.exe" },
+                    {
+                        "from": "/build/b.exe",
+                        "through": "/share/b.exe",
+                        "to": "/rig/b.exe",
+                        "retry": { "max_attempts": 2, "timeout_ms": 1000 }
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        let global = &configuration.files[0].options.retry;
+        assert_eq!(5, global.max_attempts);
+        assert_eq!(Backoff::Exponential, global.backoff);
+        assert_eq!(Duration::from_millis(50), global.delay);
+        assert_eq!(None, global.timeout);
+        let own = &configuration.files[1].options.retry;
+        assert_eq!(2, own.max_attempts);
+        assert_eq!(Backoff::Exponential, own.backoff);
+        assert_eq!(Some(Duration::from_millis(1000)), own.timeout);
+
+        let error = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "from": "/build/a.exe",
+                        "through": "/share/a.exe",
+                        "to": "/rig/a.exe",
+                        "retry": { "max_attempts": 0 }
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("max_attempts"));
+    }
+
+    #[test]
+    fn test_settle() {
+        let configuration = parse_configuration_from_string(
+            r#"
+                [[files]]
+                from = "/build/a.exe"
+                through = "/share/a.exe"
+                to = "/rig/a.exe"
+                settle = { strategy = "stable", duration_ms = 500 }
+
+                [[files]]
+                from = "/build/b.exe"
+                through = "/share/b.exe"
+                to = "/rig/b.exe"
+                settle = { strategy = "exclusive_open" }
+
+                [[files]]
+                from = "/build/c.exe"
+                through = "/share/c.exe"
+                to = "/rig/c.exe"
+            "#,
+            ConfigurationFormat::Toml,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            Settle::Stable(Duration::from_millis(500)),
+            configuration.files[0].options.settle
+        );
+        assert_eq!(Settle::ExclusiveOpen, configuration.files[1].options.settle);
+        assert_eq!(Settle::Immediately, configuration.files[2].options.settle);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let contents = r#"{
+            "files": [
+                {
+                    "from": "/build/a.exe",
+                    "through": "/share/a.exe",
+                    "to": "/rig/a.exe",
+                    "verify": "blake3",
+                    "checksums": true,
+                    "handoff": true
+                }
+            ]
+        }"#;
+        let start = parse_configuration_from_string(
+            contents,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        let options = &start.files[0].options;
+        assert_eq!(Some(HashAlgorithm::Blake3), options.verify);
+        assert!(options.write_checksum);
+        assert!(!options.check_checksum);
+        assert!(options.write_handoff);
+        assert!(!options.check_handoff);
+
+        let end = parse_configuration_from_string(
+            contents,
+            ConfigurationFormat::Json,
+            &StepInChain::End,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        let options = &end.files[0].options;
+        assert!(!options.write_checksum);
+        assert!(options.check_checksum);
+        assert!(!options.write_handoff);
+        assert!(options.check_handoff);
+    }
+
+    #[test]
+    fn test_groups() {
+        let parsed = parse_configuration_from_string(
+            r#"{
+                "files": [
+                    {
+                        "from": "/build/app.{exe,pdb}",
+                        "through": "/share/app.{exe,pdb}",
+                        "to": "/rig/app.{exe,pdb}"
+                    },
+                    {
+                        "from": "/build/server.exe",
+                        "through": "/share/server.exe",
+                        "to": "/rig/server.exe",
+                        "group": "server"
+                    },
+                    {
+                        "from": "/build/server.dll",
+                        "through": "/share/server.dll",
+                        "to": "/rig/server.dll",
+                        "group": "server"
+                    },
+                    {
+                        "from": "/build/readme.txt",
+                        "through": "/share/readme.txt",
+                        "to": "/rig/readme.txt"
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        let groups: Vec<Option<Group>> = parsed
+            .files
+            .iter()
+            .map(|f| f.options.group.clone())
+            .collect();
+        let server = Some(Group::Named(String::from("server")));
+        assert_eq!(
+            vec![
+                Some(Group::Entry(1)),
+                Some(Group::Entry(1)),
+                server.clone(),
+                server,
+                None
+            ],
+            groups
+        );
+    }
+
+    #[test]
+    fn test_remove_and_rename_policies() {
+        let parsed = parse_configuration_from_string(
+            r#"{
+                "aliases": [{ "name": "backup", "replacement": "/backup" }],
+                "files": [
+                    {
+                        "from": "/build/app.exe",
+                        "through": "/share/app.exe",
+                        "to": "/rig/app.exe"
+                    },
+                    {
+                        "from": "/build/lib.dll",
+                        "through": "/share/lib.dll",
+                        "to": "/rig/lib.dll",
+                        "on_remove": { "action": "quarantine", "directory": "@backup/quarantine" },
+                        "on_rename": "rename"
+                    },
+                    {
+                        "kind": "directory",
+                        "from": "/build/bin",
+                        "through": "/share/bin",
+                        "to": "/rig/bin"
+                    },
+                    {
+                        "kind": "directory",
+                        "from": "/build/doc",
+                        "through": "/share/doc",
+                        "to": "/rig/doc",
+                        "on_remove": { "action": "keep" },
+                        "on_rename": "copy"
+                    }
+                ]
+            }"#,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
+        )
+        .unwrap();
+        let files = &parsed.files;
+        assert_eq!(RemovePolicy::Keep, files[0].options.on_remove);
+        assert_eq!(RenamePolicy::Copy, files[0].options.on_rename);
+        assert_eq!(
+            RemovePolicy::Quarantine(PathBuf::from("/backup/quarantine")),
+            files[1].options.on_remove
+        );
+        assert_eq!(RenamePolicy::Rename, files[1].options.on_rename);
+        let directories = &parsed.directories;
+        assert_eq!(RemovePolicy::Delete, directories[0].options.on_remove);
+        assert_eq!(RenamePolicy::Rename, directories[0].options.on_rename);
+        assert_eq!(RemovePolicy::Keep, directories[1].options.on_remove);
+        assert_eq!(RenamePolicy::Copy, directories[1].options.on_rename);
+    }
+
+    #[test]
+    fn test_path_resolution() {
+        let contents = |path_resolution: &str| {
+            format!(
+                r#"{{
+                    "path_resolution": "{}",
+                    "steps": [{{ "name": "end", "base_dir": "../rig" }}],
+                    "files": [
+                        {{
+                            "from": "bin/app.exe",
+                            "through": "/share/app.exe",
+                            "to": "app.exe"
+                        }}
+                    ]
+                }}"#,
+                path_resolution
+            )
+        };
+        let directory = Path::new("/configs");
+        let sources = |contents: &str, step: &StepInChain| -> Vec<(PathBuf, PathBuf)> {
+            parse_configuration_from_string(
+                contents,
+                ConfigurationFormat::Json,
+                step,
+                &HashMap::new(),
+                Some(directory),
+            )
+            .unwrap()
+            .files
+            .iter()
+            .map(|f| (f.from.clone(), f.to.clone()))
+            .collect()
+        };
+
+        let start = sources(&contents("configuration"), &StepInChain::Start);
+        assert_eq!(directory.join("bin/app.exe"), start[0].0);
+        assert_eq!(PathBuf::from("/share/app.exe"), start[0].1);
+
+        // The step has a base directory of its own, relative to the configuration.
+        let end = sources(&contents("configuration"), &StepInChain::End);
+        assert_eq!(directory.join("../rig").join("app.exe"), end[0].1);
+
+        let start = sources(&contents("working_directory"), &StepInChain::Start);
+        assert_eq!(PathBuf::from("bin/app.exe"), start[0].0);
+        let end = sources(&contents("working_directory"), &StepInChain::End);
+        assert_eq!(PathBuf::from("../rig").join("app.exe"), end[0].1);
+
+        // Configurations with steps or locations are relative to the configuration by default,
+        // older ones to the current directory.
+        let unset = contents("configuration").replace(r#""path_resolution": "configuration","#, "");
+        let start = sources(&unset, &StepInChain::Start);
+        assert_eq!(directory.join("bin/app.exe"), start[0].0);
+        let legacy = r#"{
+            "files": [{ "from": "bin/app.exe", "through": "/share/app.exe", "to": "app.exe" }]
+        }"#;
+        let start = sources(legacy, &StepInChain::Start);
+        assert_eq!(PathBuf::from("bin/app.exe"), start[0].0);
+
+        // Drive letters and UNC paths are absolute, on the hosts that have them.
+        if cfg!(windows) {
+            let windows = r#"{
+                "path_resolution": "configuration",
+                "files": [
+                    { "from": "C:\\bin\\lib.dll", "through": "/share/lib.dll", "to": "\\\\rig\\lib.dll" }
+                ]
+            }"#;
+            let start = sources(windows, &StepInChain::Start);
+            assert_eq!(native("C:\\bin\\lib.dll"), start[0].0);
+        }
+    }
+
+    #[test]
+    fn test_mounts() {
+        let contents = r#"{
+            "mounts": [
+                { "prefix": "Z:", "path": "/mnt/share" },
+                { "prefix": "\\\\fileserver\\builds", "path": "/mnt/builds" }
+            ],
+            "steps": [
+                { "name": "end", "mounts": [{ "prefix": "z:", "path": "/mnt/rig" }] }
+            ],
+            "files": [
+                {
+                    "from": "\\\\FileServer\\Builds\\@version\\app.exe",
+                    "through": "Z:\\app.exe",
+                    "to": "C:\\bin\\app.exe",
+                    "on_remove": { "action": "quarantine", "directory": "Z:\\quarantine" }
+                }
+            ]
+        }"#;
+        let aliases: HashMap<String, String> =
+            [(String::from("version"), String::from("1.0"))].into();
+        let parse = |step: &StepInChain| {
+            parse_configuration_from_string(
+                contents,
+                ConfigurationFormat::Json,
+                step,
+                &aliases,
+                None,
+            )
+        };
+
+        let start = parse(&StepInChain::Start).unwrap();
+        let file = start.files.first().unwrap();
+        assert_eq!(native("/mnt/builds/1.0/app.exe"), file.from);
+        assert_eq!(native("/mnt/share/app.exe"), file.to);
+        assert_eq!(
+            RemovePolicy::Quarantine(native("/mnt/share/quarantine")),
+            file.options.on_remove
+        );
+
+        // The mount of the step overrides the global one. The drive of the destination has no
+        // mount, which only works on Windows.
+        let end = parse(&StepInChain::End);
+        if cfg!(windows) {
+            let end = end.unwrap();
+            let file = end.files.first().unwrap();
+            assert_eq!(native("/mnt/rig/app.exe"), file.from);
+            assert_eq!(native("C:\\bin\\app.exe"), file.to);
+        } else {
+            assert_eq!(
+                vec![Problem::UnmappedPrefix {
+                    file: 1,
+                    path: String::from("C:\\bin\\app.exe"),
+                    prefix: String::from("C:"),
+                }],
+                problems(end)
+            );
+        }
     }
 
     #[test]
     fn test_simply_configuration() {
         let parse_result = parse_configuration_from_string(
             r#"{
+                "mounts": [{ "prefix": "C:", "path": "/mnt/c" }],
                 "aliases": [
                     {
                         "name": "shared",
@@ -383,14 +1769,22 @@ mod tests {
                     }
                 ]
             }"#,
-            StepInChain::Start,
+            ConfigurationFormat::Json,
+            &StepInChain::Start,
+            &HashMap::new(),
+            None,
         );
-        assert!(parse_result.is_ok());
-        let (configuration, unparsed) = parse_result.unwrap();
+        // The configuration is written for Windows, the mounts make it work on every host.
+        let configuration = parse_result.unwrap();
         assert_eq!(configuration.files.len(), 2);
+        let file = configuration.files.first().unwrap();
+        assert_eq!(
+            native("/mnt/c/workspaces/GroupFuelPos/git-FuelPos_53.90.9999999_stable/Common/DatabaseServer/Server/bin/Debug/Framework/DatabaseServer.exe"),
+            file.from
+        );
         assert_eq!(
-            "C:\\workspaces\\GroupFuelPos\\git-FuelPos_53.90.9999999_stable\\Common\\DatabaseServer\\Server\\bin\\Debug\\Framework\\DatabaseServer.exe",
-            configuration.files.get(0).unwrap().from.to_string_lossy()
+            native("/mnt/c/FpShare/autocopier/Files/DatabaseServer/DatabaseServer.exe"),
+            file.to
         );
     }
 
@@ -0,0 +1,342 @@
+use glob::Pattern;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::copy::FileDescription;
+use crate::rules::DirectoryDescription;
+
+/// A single problem found in the configuration. `file` is the 1-based position of the entry in
+/// the `files` list.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    InvalidFile {
+        file: usize,
+        message: String,
+    },
+    UnresolvedAlias {
+        file: usize,
+        path: String,
+        alias: String,
+    },
+    AliasCycle {
+        chain: Vec<String>,
+    },
+    MissingEnvironmentVariable {
+        file: usize,
+        path: String,
+        variable: String,
+    },
+    UnbalancedBraces {
+        file: usize,
+        path: String,
+    },
+    EmptyAlternative {
+        file: usize,
+        path: String,
+    },
+    BraceMismatch {
+        file: usize,
+        path: String,
+        other_path: String,
+    },
+    MissingPlaceholder {
+        file: usize,
+        path: String,
+    },
+    InvalidPattern {
+        file: usize,
+        path: String,
+        message: String,
+    },
+    DuplicateDestination {
+        destination: PathBuf,
+        sources: Vec<PathBuf>,
+    },
+    /// A drive letter or UNC share that isn't mapped by `mounts` on a host without them.
+    UnmappedPrefix {
+        file: usize,
+        path: String,
+        prefix: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::InvalidFile { file, message } => write!(f, "file {}: {}", file, message),
+            Problem::UnresolvedAlias { file, path, alias } => write!(
+                f,
+                "file {}: alias '@{}' in {:?} is not defined",
+                file, alias, path
+            ),
+            Problem::AliasCycle { chain } => {
+                write!(f, "aliases refer to themselves: {}", chain.join(" -> "))
+            }
+            Problem::MissingEnvironmentVariable {
+                file,
+                path,
+                variable,
+            } => write!(
+                f,
+                "file {}: environment variable '{}' in {:?} is not set",
+                file, variable, path
+            ),
+            Problem::UnbalancedBraces { file, path } => {
+                write!(
+                    f,
+                    "file {}: unbalanced or nested braces in {:?}",
+                    file, path
+                )
+            }
+            Problem::EmptyAlternative { file, path } => {
+                write!(
+                    f,
+                    "file {}: empty alternative between braces in {:?}",
+                    file, path
+                )
+            }
+            Problem::BraceMismatch {
+                file,
+                path,
+                other_path,
+            } => write!(
+                f,
+                "file {}: {:?} and {:?} do not have the same brace groups",
+                file, path, other_path
+            ),
+            Problem::MissingPlaceholder { file, path } => write!(
+                f,
+                "file {}: the destination {:?} of a pattern needs {{relpath}} or {{filename}}",
+                file, path
+            ),
+            Problem::InvalidPattern {
+                file,
+                path,
+                message,
+            } => write!(f, "file {}: invalid pattern {:?}: {}", file, path, message),
+            Problem::DuplicateDestination {
+                destination,
+                sources,
+            } => write!(
+                f,
+                "{:?} is the destination of more than one entry: {:?}",
+                destination, sources
+            ),
+            Problem::UnmappedPrefix { file, path, prefix } => write!(
+                f,
+                "file {}: {:?} starts with {:?}, which needs a mount on this host",
+                file, path, prefix
+            ),
+        }
+    }
+}
+
+/// Every problem found in the configuration, so they can all be reported at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationReport {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The configuration has {} problem(s):",
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            write!(f, "\n\t{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ValidationReport {}
+
+/// Splits the brace groups out of `path`, e.g. `a.{exe,pdb}` gives `[["exe", "pdb"]]`.
+/// Returns `None` if the braces are unbalanced or nested.
+pub fn brace_groups(path: &str) -> Option<Vec<Vec<&str>>> {
+    let mut groups = Vec::new();
+    let mut rest = path;
+    loop {
+        let start = rest.find('{');
+        let end = rest.find('}');
+        match (start, end) {
+            (None, None) => return Some(groups),
+            (Some(start), Some(end)) if start < end => {
+                let group = &rest[start + 1..end];
+                if group.contains('{') {
+                    return None;
+                }
+                groups.push(group.split(',').collect());
+                rest = &rest[end + 1..];
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Checks that every location of a file has the same brace groups, with no empty alternatives.
+/// Only when this holds can the expanded paths be paired up one to one.
+pub fn check_braces(file: usize, paths: &[String], problems: &mut Vec<Problem>) {
+    let mut first: Option<(&String, Vec<usize>)> = None;
+    for path in paths {
+        let groups = match brace_groups(path) {
+            Some(groups) => groups,
+            None => {
+                problems.push(Problem::UnbalancedBraces {
+                    file,
+                    path: path.to_owned(),
+                });
+                continue;
+            }
+        };
+        if groups
+            .iter()
+            .flatten()
+            .any(|alternative| alternative.is_empty())
+        {
+            problems.push(Problem::EmptyAlternative {
+                file,
+                path: path.to_owned(),
+            });
+        }
+        let shape: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        match &first {
+            None => first = Some((path, shape)),
+            Some((first_path, first_shape)) => {
+                if *first_shape != shape {
+                    problems.push(Problem::BraceMismatch {
+                        file,
+                        path: first_path.to_string(),
+                        other_path: path.to_owned(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Compiles the include or exclude patterns of a directory.
+pub fn compile_patterns(
+    file: usize,
+    patterns: &Option<Vec<String>>,
+    problems: &mut Vec<Problem>,
+) -> Vec<Pattern> {
+    let mut result: Vec<Pattern> = Vec::new();
+    for pattern in patterns.iter().flatten() {
+        match Pattern::new(pattern) {
+            Ok(v) => result.push(v),
+            Err(e) => problems.push(Problem::InvalidPattern {
+                file,
+                path: pattern.to_owned(),
+                message: e.msg.to_owned(),
+            }),
+        }
+    }
+    result
+}
+
+/// Checks that no two expanded files or directories share a destination. A source may be copied
+/// to several destinations. The destinations of patterns depend on the files that match them, so
+/// they aren't known up front.
+pub fn check_duplicates(
+    files: &[FileDescription],
+    directories: &[DirectoryDescription],
+    problems: &mut Vec<Problem>,
+) {
+    let rules: Vec<(&PathBuf, &PathBuf)> = files
+        .iter()
+        .map(|fd| (&fd.from, &fd.to))
+        .chain(directories.iter().map(|d| (&d.from, &d.to)))
+        .collect();
+    let mut destinations: HashMap<&PathBuf, Vec<PathBuf>> = HashMap::new();
+    for (from, to) in &rules {
+        destinations.entry(to).or_default().push((*from).clone());
+    }
+    for (_, to) in &rules {
+        if let Some(from) = destinations.remove(to) {
+            if from.len() > 1 {
+                problems.push(Problem::DuplicateDestination {
+                    destination: (*to).clone(),
+                    sources: from,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::CopyOptions;
+
+    #[test]
+    fn test_brace_groups() {
+        assert_eq!(Some(vec![]), brace_groups("executable.exe"));
+        assert_eq!(
+            Some(vec![vec!["Debug", "Release"], vec!["exe", "pdb"]]),
+            brace_groups("bin\\{Debug,Release}\\executable.{exe,pdb}")
+        );
+        assert_eq!(None, brace_groups("executable.{exe,pdb"));
+        assert_eq!(None, brace_groups("executable.}exe,pdb{"));
+        assert_eq!(None, brace_groups("executable.{exe,{pdb}}"));
+    }
+
+    #[test]
+    fn test_check_braces() {
+        let mut problems = Vec::new();
+        check_braces(
+            1,
+            &[
+                String::from("a.{exe,pdb}"),
+                String::from("b.{exe,pdb}"),
+                String::from("c.{exe,pdb,config}"),
+            ],
+            &mut problems,
+        );
+        assert_eq!(
+            problems,
+            vec![Problem::BraceMismatch {
+                file: 1,
+                path: String::from("a.{exe,pdb}"),
+                other_path: String::from("c.{exe,pdb,config}"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_duplicates() {
+        let file = |from: &str, to: &str| FileDescription {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+            options: CopyOptions::default(),
+        };
+        let directory = |from: &str, to: &str| DirectoryDescription {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            delete_extraneous: false,
+            options: CopyOptions::default(),
+        };
+        let mut problems = Vec::new();
+        check_duplicates(
+            &[
+                file("bin/app.exe", "share/app.exe"),
+                file("bin/lib", "share/lib"),
+            ],
+            &[directory("bin", "rig"), directory("obj/lib", "share/lib")],
+            &mut problems,
+        );
+        assert_eq!(
+            problems,
+            vec![Problem::DuplicateDestination {
+                destination: PathBuf::from("share/lib"),
+                sources: vec![PathBuf::from("bin/lib"), PathBuf::from("obj/lib")],
+            }]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// The extension of the checksum file written next to a copied file.
+pub const SIDECAR_EXTENSION: &str = "sha256";
+/// The extension of the handoff marker written next to a copied file.
+pub const MARKER_EXTENSION: &str = "handoff";
+
+/// Hashes the content of the file at `path`.
+pub fn hash_file<P: AsRef<Path>>(path: P) -> io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// The hash used to verify that a copy has the same content as its source.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+/// Hashes the content of the file at `path` with `algorithm`, as lowercase hexadecimal.
+pub fn checksum<P: AsRef<Path>>(path: P, algorithm: HashAlgorithm) -> io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            io::copy(&mut File::open(path)?, &mut hasher)?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        HashAlgorithm::Blake3 => Ok(hash_file(path)?.to_hex().to_string()),
+    }
+}
+
+/// The size and hashes of the content that was copied, computed while copying it, so the source
+/// doesn't have to be read again and they describe exactly what was copied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Digests {
+    pub size: u64,
+    pub blake3: blake3::Hash,
+    /// Only computed when the rule needs it, since it is much slower than BLAKE3.
+    pub sha256: Option<String>,
+}
+
+impl Digests {
+    pub fn checksum(&self, algorithm: HashAlgorithm) -> Option<String> {
+        match algorithm {
+            HashAlgorithm::Sha256 => self.sha256.clone(),
+            HashAlgorithm::Blake3 => Some(self.blake3.to_hex().to_string()),
+        }
+    }
+}
+
+/// Copies everything from `source` to `destination`, hashing it on the way.
+pub fn copy_hashing(
+    source: &mut impl Read,
+    destination: &mut impl Write,
+    sha256: bool,
+) -> io::Result<Digests> {
+    let mut blake3 = blake3::Hasher::new();
+    let mut sha256 = if sha256 { Some(Sha256::new()) } else { None };
+    let mut size: u64 = 0;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        destination.write_all(&buffer[..read])?;
+        blake3.update(&buffer[..read]);
+        if let Some(hasher) = &mut sha256 {
+            hasher.update(&buffer[..read]);
+        }
+        size += read as u64;
+    }
+    Ok(Digests {
+        size,
+        blake3: blake3.finalize(),
+        sha256: sha256.map(|hasher| format!("{:x}", hasher.finalize())),
+    })
+}
+
+/// Checks that the copy at `to` has the content that was copied, as hashed while copying it.
+pub fn verify(to: &Path, copied: &Digests, algorithm: HashAlgorithm) -> io::Result<()> {
+    let expected = copied.checksum(algorithm).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("the {:?} of {:?} wasn't computed", algorithm, to),
+        )
+    })?;
+    let actual = checksum(to, algorithm)?;
+    if expected != actual {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("checksum of {:?} doesn't match its source", to),
+        ));
+    }
+    Ok(())
+}
+
+/// The checksum file of `path`, e.g. `app.exe.sha256` for `app.exe`.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(SIDECAR_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+pub fn is_sidecar(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e == SIDECAR_EXTENSION)
+        .unwrap_or(false)
+}
+
+/// The contents of the checksum file of `to`, in the format of `sha256sum`.
+pub fn sidecar_line(to: &Path, sha256: &str) -> String {
+    format!(
+        "{}  {}\n",
+        sha256,
+        to.file_name().unwrap_or_default().to_string_lossy()
+    )
+}
+
+/// Writes the checksum `sha256` of `to` next to it.
+pub fn write_sidecar(to: &Path, sha256: &str) -> io::Result<()> {
+    let line = sidecar_line(to, sha256);
+    let sidecar = sidecar_path(to);
+    let mut temporary = sidecar.as_os_str().to_os_string();
+    temporary.push(".");
+    temporary.push(crate::copy::FileDescription::TEMPORARY_EXTENSION);
+    fs::write(&temporary, line)?;
+    fs::rename(&temporary, &sidecar)
+}
+
+/// Checks that `sha256` is the checksum in the checksum file of `path`.
+pub fn check_sidecar_checksum(path: &Path, sha256: &str) -> io::Result<()> {
+    let sidecar = sidecar_path(path);
+    let contents = fs::read_to_string(&sidecar)?;
+    let expected = contents.split_whitespace().next().unwrap_or_default();
+    if !expected.eq_ignore_ascii_case(sha256) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} doesn't match the checksum in {:?}", path, sidecar),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks `path` against the checksum file next to it.
+pub fn check_sidecar(path: &Path) -> io::Result<()> {
+    check_sidecar_checksum(path, &checksum(path, HashAlgorithm::Sha256)?)
+}
+
+/// Written next to a file in a location in between, after the file is completely copied. The
+/// next step only copies the file onwards when it matches its marker, and only once for every
+/// generation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HandoffMarker {
+    pub generation: u64,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// The handoff marker of `path`, e.g. `app.exe.handoff` for `app.exe`.
+pub fn marker_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(MARKER_EXTENSION);
+    path.with_file_name(file_name)
+}
+
+/// Returns the file that `path` is the handoff marker of, if it is one.
+pub fn marked_file(path: &Path) -> Option<PathBuf> {
+    if path.extension()? != MARKER_EXTENSION {
+        return None;
+    }
+    Some(path.with_file_name(path.file_stem()?))
+}
+
+pub fn read_marker(path: &Path) -> io::Result<HandoffMarker> {
+    let contents = fs::read_to_string(marker_path(path))?;
+    serde_json::from_str(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Marks `to` as completely copied, with the content `sha256` that was copied to it.
+pub fn write_marker(to: &Path, sha256: String) -> io::Result<()> {
+    let generation = read_marker(to).map(|m| m.generation + 1).unwrap_or(1);
+    let marker = HandoffMarker {
+        generation,
+        size: fs::metadata(to)?.len(),
+        sha256,
+    };
+    let contents =
+        serde_json::to_string(&marker).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let marker = marker_path(to);
+    let mut temporary = marker.as_os_str().to_os_string();
+    temporary.push(".");
+    temporary.push(crate::copy::FileDescription::TEMPORARY_EXTENSION);
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, &marker)
+}
+
+/// Whether `path` has the content its marker describes, so its generation is complete.
+pub fn matches_marker(path: &Path, marker: &HandoffMarker) -> io::Result<bool> {
+    if fs::metadata(path)?.len() != marker.size {
+        return Ok(false);
+    }
+    Ok(checksum(path, HashAlgorithm::Sha256)?.eq_ignore_ascii_case(&marker.sha256))
+}
+
+/// The checksum file and the handoff marker that may have been written next to `path`.
+pub fn companions(path: &Path) -> [PathBuf; 2] {
+    [sidecar_path(path), marker_path(path)]
+}
+
+/// What a destination contained when we last looked at it.
+#[derive(Debug, Clone, PartialEq)]
+struct KnownContent {
+    size: u64,
+    modified: SystemTime,
+    hash: blake3::Hash,
+}
+
+/// The content of the destinations that were copied to or compared with, so comparing an
+/// unchanged source doesn't require reading the destination again. An entry is only trusted as
+/// long as the size and modification time of the destination stay the same.
+///
+/// The cache is shared by every copy that runs at the same time. It is only locked to look up or
+/// remember an entry, never while a file is hashed.
+#[derive(Debug, Default)]
+pub struct ContentCache {
+    destinations: Mutex<HashMap<PathBuf, KnownContent>>,
+}
+
+impl ContentCache {
+    fn lock(&self) -> MutexGuard<'_, HashMap<PathBuf, KnownContent>> {
+        self.destinations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the hash of the destination `to`, from the cache if it is still valid.
+    fn destination_hash(&self, to: &Path) -> io::Result<blake3::Hash> {
+        let metadata = fs::metadata(to)?;
+        let modified = metadata.modified()?;
+        if let Some(known) = self.lock().get(to) {
+            if known.size == metadata.len() && known.modified == modified {
+                return Ok(known.hash);
+            }
+        }
+        let hash = hash_file(to)?;
+        self.lock().insert(
+            to.to_path_buf(),
+            KnownContent {
+                size: metadata.len(),
+                modified,
+                hash,
+            },
+        );
+        Ok(hash)
+    }
+
+    /// Whether `to` already has the same content as `from`.
+    pub fn is_unchanged(&self, from: &Path, to: &Path) -> io::Result<bool> {
+        let source_size = fs::metadata(from)?.len();
+        let destination_size = match fs::metadata(to) {
+            Ok(v) => v.len(),
+            Err(_) => return Ok(false),
+        };
+        if source_size != destination_size {
+            return Ok(false);
+        }
+        Ok(hash_file(from)? == self.destination_hash(to)?)
+    }
+
+    /// Remembers that `to` was just copied, with content that hashed to `hash` while copying.
+    pub fn copied(&self, to: &Path, hash: blake3::Hash) {
+        let known = (|| {
+            let metadata = fs::metadata(to)?;
+            Ok::<KnownContent, io::Error>(KnownContent {
+                size: metadata.len(),
+                modified: metadata.modified()?,
+                hash,
+            })
+        })();
+        match known {
+            Ok(v) => {
+                self.lock().insert(to.to_path_buf(), v);
+            }
+            Err(_) => {
+                self.lock().remove(to);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_content_cache() {
+        let base = TempDir::new("content_cache");
+        let from = base.join("app.pdb");
+        let to = base.join("copy.pdb");
+        fs::write(&from, b"Same").unwrap();
+
+        let cache = ContentCache::default();
+        assert!(!cache.is_unchanged(&from, &to).unwrap());
+        fs::write(&to, b"Same").unwrap();
+        assert!(cache.is_unchanged(&from, &to).unwrap());
+        fs::write(&from, b"Diff").unwrap();
+        assert!(!cache.is_unchanged(&from, &to).unwrap());
+
+        fs::copy(&from, &to).unwrap();
+        cache.copied(&to, hash_file(&from).unwrap());
+        assert!(cache.is_unchanged(&from, &to).unwrap());
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let base = TempDir::new("sidecar");
+        let from = base.join("app.exe");
+        let to = base.join("share.exe");
+        fs::write(&from, b"Test").unwrap();
+        fs::write(&to, b"Test").unwrap();
+
+        assert_eq!(
+            "532eaabd9574880dbf76b9b8cc00832c20a6ec113d682299550d7a6e0f345e25",
+            checksum(&from, HashAlgorithm::Sha256).unwrap()
+        );
+        let digests = copy_hashing(
+            &mut File::open(&from).unwrap(),
+            &mut File::create(&to).unwrap(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(4, digests.size);
+        assert_eq!(checksum(&from, HashAlgorithm::Sha256).ok(), digests.sha256);
+        verify(&to, &digests, HashAlgorithm::Blake3).unwrap();
+
+        assert_eq!(base.join("share.exe.sha256"), sidecar_path(&to));
+        assert!(is_sidecar(&sidecar_path(&to)));
+        assert_eq!(ErrorKind::NotFound, check_sidecar(&to).unwrap_err().kind());
+        write_sidecar(&to, digests.sha256.as_deref().unwrap()).unwrap();
+        assert!(fs::read_to_string(sidecar_path(&to))
+            .unwrap()
+            .ends_with("  share.exe\n"));
+        check_sidecar(&to).unwrap();
+
+        fs::write(&to, b"Corrupt").unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            check_sidecar(&to).unwrap_err().kind()
+        );
+        assert!(verify(&to, &digests, HashAlgorithm::Sha256).is_err());
+    }
+
+    #[test]
+    fn test_handoff_marker() {
+        let base = TempDir::new("handoff_marker");
+        let path = base.join("app.exe");
+        fs::write(&path, b"Test").unwrap();
+
+        assert_eq!(base.join("app.exe.handoff"), marker_path(&path));
+        assert_eq!(Some(path.clone()), marked_file(&marker_path(&path)));
+        assert_eq!(None, marked_file(&path));
+
+        let sha256 = checksum(&path, HashAlgorithm::Sha256).unwrap();
+        write_marker(&path, sha256.clone()).unwrap();
+        write_marker(&path, sha256).unwrap();
+        let marker = read_marker(&path).unwrap();
+        assert_eq!(2, marker.generation);
+        assert!(matches_marker(&path, &marker).unwrap());
+
+        // Still being written.
+        fs::write(&path, b"Tes").unwrap();
+        assert!(!matches_marker(&path, &marker).unwrap());
+    }
+}
@@ -0,0 +1,970 @@
+use crate::content::{self, ContentCache, Digests, HashAlgorithm};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq)]
+pub struct FileDescription {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub options: CopyOptions,
+}
+
+/// The files that are copied together.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Group {
+    /// The entries with this `group` in the configuration.
+    Named(String),
+    /// The files the entry with this number expands to with braces.
+    Entry(usize),
+}
+
+/// How the files of a rule are copied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyOptions {
+    /// Copy to a temporary file next to the destination first and rename it over the
+    /// destination once it is complete, so a half-written file is never seen.
+    pub atomic: bool,
+    pub retry: RetryPolicy,
+    pub settle: Settle,
+    /// Don't copy when the destination already has the same content.
+    pub skip_unchanged: bool,
+    /// Compare the checksums of the source and the copy, and copy again when they differ.
+    pub verify: Option<HashAlgorithm>,
+    /// Write a checksum file next to the destination, for the next hop to check.
+    pub write_checksum: bool,
+    /// Check the source against the checksum file next to it before copying it.
+    pub check_checksum: bool,
+    /// Write a handoff marker next to the destination once it is completely copied.
+    pub write_handoff: bool,
+    /// Only copy the source when its handoff marker says it is complete.
+    pub check_handoff: bool,
+    /// The files of a group are only copied together, once all of them settled, and none of
+    /// them are when one fails.
+    pub group: Option<Group>,
+    pub on_remove: RemovePolicy,
+    pub on_rename: RenamePolicy,
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            atomic: true,
+            retry: RetryPolicy::default(),
+            settle: Settle::default(),
+            skip_unchanged: true,
+            verify: None,
+            write_checksum: false,
+            check_checksum: false,
+            write_handoff: false,
+            check_handoff: false,
+            group: None,
+            on_remove: RemovePolicy::default(),
+            on_rename: RenamePolicy::default(),
+        }
+    }
+}
+
+impl CopyOptions {
+    /// Whether `path` is a checksum file or a handoff marker of this rule, which belongs to the
+    /// file it is next to and isn't copied itself.
+    pub fn is_companion(&self, path: &Path) -> bool {
+        let checksum = (self.write_checksum || self.check_checksum) && content::is_sidecar(path);
+        let marker =
+            (self.write_handoff || self.check_handoff) && content::marked_file(path).is_some();
+        checksum || marker
+    }
+}
+
+/// What happens to the destination when its source is removed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum RemovePolicy {
+    /// Leave the destination as it is.
+    #[default]
+    Keep,
+    Delete,
+    /// Move the destination into this directory, with the time it was removed in its name.
+    Quarantine(PathBuf),
+}
+
+/// What happens to the destination when its source is renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RenamePolicy {
+    /// Copy the renamed source to its new destination, and handle the old destination like the
+    /// source was removed.
+    #[default]
+    Copy,
+    /// Rename the destination as well.
+    Rename,
+}
+
+/// What was done with a destination, because its source was removed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Removal {
+    Kept,
+    Deleted,
+    /// Moved to this path.
+    Quarantined(PathBuf),
+}
+
+/// How a copy ended, when it didn't fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyOutcome {
+    /// Copied, in this many attempts.
+    Copied(u32),
+    /// The destination already had the same content.
+    Skipped,
+}
+
+/// When a changed source is considered completely written, so it can be copied.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Settle {
+    /// Copy as soon as a change is seen.
+    #[default]
+    Immediately,
+    /// Wait until the size and modification time haven't changed for this long.
+    Stable(Duration),
+    /// Wait until the source can be opened exclusively, which fails while it is still being
+    /// written on Windows. Elsewhere this only waits until the source stops changing.
+    ExclusiveOpen,
+}
+
+/// How the delay between two attempts grows.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backoff {
+    /// Always wait the same delay.
+    #[default]
+    Fixed,
+    /// Double the delay after every attempt, up to the maximum delay.
+    Exponential,
+}
+
+/// How often, and for how long, a failing copy is attempted again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Give up once this much time has passed since the first attempt.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 100,
+            backoff: Backoff::Fixed,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(10),
+            timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay after the given failed attempt, counting from 1.
+    pub fn delay_after(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed => self.delay,
+            Backoff::Exponential => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                self.delay
+                    .checked_mul(factor)
+                    .unwrap_or(self.max_delay)
+                    .min(self.max_delay)
+            }
+        }
+    }
+}
+
+impl FileDescription {
+    pub const TEMPORARY_EXTENSION: &'static str = "autocopier.tmp";
+
+    /// Whether `path` is the temporary file of an atomic copy, which should never be picked up.
+    pub fn is_temporary<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref()
+            .file_name()
+            .map(|n| {
+                n.to_string_lossy()
+                    .ends_with(FileDescription::TEMPORARY_EXTENSION)
+            })
+            .unwrap_or(false)
+    }
+
+    /// The hidden file next to the destination that an atomic copy writes to.
+    fn temporary_path(&self) -> PathBuf {
+        FileDescription::hidden_path(&self.to, FileDescription::TEMPORARY_EXTENSION)
+    }
+
+    /// Whether nobody else has the source open, as far as the platform can tell.
+    pub fn can_open_exclusively(&self) -> bool {
+        let mut options = fs::OpenOptions::new();
+        options.read(true);
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::OpenOptionsExt;
+            options.share_mode(0);
+        }
+        options.open(&self.from).is_ok()
+    }
+
+    /// Whether the rule needs the SHA-256 of what it copies.
+    fn needs_sha256(&self) -> bool {
+        let options = &self.options;
+        options.write_checksum
+            || options.write_handoff
+            || options.check_checksum
+            || options.check_handoff
+            || options.verify == Some(HashAlgorithm::Sha256)
+    }
+
+    /// Copies the source to `path` and flushes it to disk, hashing the content on the way. The
+    /// copy is verified if the rule asks for it.
+    fn write_copy(&self, path: &Path) -> std::io::Result<Digests> {
+        let mut source = File::open(&self.from)?;
+        let mut destination = File::create(path)?;
+        let digests = content::copy_hashing(&mut source, &mut destination, self.needs_sha256())?;
+        destination.set_permissions(source.metadata()?.permissions())?;
+        destination.sync_all()?;
+        drop(destination);
+        if let Some(algorithm) = self.options.verify {
+            content::verify(path, &digests, algorithm)?;
+        }
+        Ok(digests)
+    }
+
+    /// Copies the source to the temporary file next to the destination. Returns the temporary
+    /// file with the hashes of its content, once it is checked against the checksum file and
+    /// handoff marker of the source.
+    fn stage(&self) -> std::io::Result<(PathBuf, Digests)> {
+        let temporary = self.temporary_path();
+        let result = self.write_copy(&temporary).and_then(|digests| {
+            self.check_copied(&digests)?;
+            Ok(digests)
+        });
+        match result {
+            Ok(digests) => Ok((temporary, digests)),
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                Err(e)
+            }
+        }
+    }
+
+    /// Copies the file, unless the destination already has the same content.
+    pub fn copy(&self) -> std::io::Result<CopyOutcome> {
+        self.copy_with(&ContentCache::default())
+    }
+
+    /// Copies the file like `copy`, comparing the content with the help of `cache`.
+    pub fn copy_with(&self, cache: &ContentCache) -> std::io::Result<CopyOutcome> {
+        if self.is_unchanged(cache) {
+            return Ok(CopyOutcome::Skipped);
+        }
+        let (digests, attempts) = self.with_retries(|| self.copy_once())?;
+        if self.options.skip_unchanged {
+            cache.copied(&self.to, digests.blake3);
+        }
+        Ok(CopyOutcome::Copied(attempts))
+    }
+
+    /// Whether the copy can be skipped, because the destination already has the same content.
+    fn is_unchanged(&self, cache: &ContentCache) -> bool {
+        // Without its checksum file or marker, the next hop would never take an unchanged
+        // destination.
+        let has_checksum = !self.options.write_checksum || content::sidecar_path(&self.to).exists();
+        let has_marker = !self.options.write_handoff || content::marker_path(&self.to).exists();
+        self.options.skip_unchanged
+            && has_checksum
+            && has_marker
+            && cache.is_unchanged(&self.from, &self.to).unwrap_or(false)
+    }
+
+    /// Checks what was copied from the source against the checksum file and the handoff marker
+    /// of the source, if the rule has them. Checking the copied content rather than the source
+    /// itself means a source that changes while it is copied can't slip through.
+    fn check_copied(&self, copied: &Digests) -> std::io::Result<()> {
+        let sha256 = copied.sha256.as_deref().unwrap_or_default();
+        if self.options.check_checksum {
+            content::check_sidecar_checksum(&self.from, sha256)?;
+        }
+        if self.options.check_handoff {
+            let marker = content::read_marker(&self.from)?;
+            if marker.size != copied.size || !marker.sha256.eq_ignore_ascii_case(sha256) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{:?} doesn't match generation {} of its marker",
+                        self.from, marker.generation
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes a single attempt at copying the file, including the checksums and the handoff. The
+    /// checksum file and the marker describe the content that was copied, as hashed while
+    /// copying it.
+    fn copy_once(&self) -> std::io::Result<Digests> {
+        if let Some(parent) = self.to.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let digests = if self.options.atomic {
+            let (temporary, digests) = self.stage()?;
+            let result = (|| {
+                // The checksum goes first, so it is already there when the next hop sees the file
+                // change.
+                if let (true, Some(sha256)) = (self.options.write_checksum, &digests.sha256) {
+                    content::write_sidecar(&self.to, sha256)?;
+                }
+                fs::rename(&temporary, &self.to)
+            })();
+            if let Err(e) = result {
+                let _ = fs::remove_file(&temporary);
+                return Err(e);
+            }
+            digests
+        } else {
+            // Without a temporary file the destination is already overwritten, so the source is
+            // checked up front as well, and the checksum can only follow the copy.
+            self.check_source()?;
+            let digests = self.write_copy(&self.to)?;
+            self.check_copied(&digests)?;
+            if let (true, Some(sha256)) = (self.options.write_checksum, &digests.sha256) {
+                content::write_sidecar(&self.to, sha256)?;
+            }
+            digests
+        };
+        // The marker goes last, it tells the next hop the copy is complete.
+        if let (true, Some(sha256)) = (self.options.write_handoff, &digests.sha256) {
+            content::write_marker(&self.to, sha256.to_owned())?;
+        }
+        Ok(digests)
+    }
+
+    /// Checks the source itself against its checksum file and handoff marker, for copies that
+    /// can't check what they copied before it reaches the destination.
+    fn check_source(&self) -> std::io::Result<()> {
+        if self.options.check_checksum {
+            content::check_sidecar(&self.from)?;
+        }
+        if self.options.check_handoff {
+            let marker = content::read_marker(&self.from)?;
+            if !content::matches_marker(&self.from, &marker)? {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{:?} doesn't match generation {} of its marker",
+                        self.from, marker.generation
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes attempts according to the retry policy. Returns the result of the attempt that
+    /// succeeded and the number of attempts it took, or the last error once it gave up.
+    fn with_retries<T>(
+        &self,
+        mut attempt: impl FnMut() -> std::io::Result<T>,
+    ) -> std::io::Result<(T, u32)> {
+        let retry = &self.options.retry;
+        let started = Instant::now();
+        let mut attempts: u32 = 0;
+        loop {
+            attempts += 1;
+            let e = match attempt() {
+                Ok(v) => return Ok((v, attempts)),
+                Err(e) => e,
+            };
+
+            let delay = retry.delay_after(attempts);
+            let timed_out = retry
+                .timeout
+                .map(|timeout| started.elapsed() + delay > timeout)
+                .unwrap_or(false);
+            if attempts >= retry.max_attempts || timed_out {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("gave up after {} attempt(s): {}", attempts, e),
+                ));
+            }
+            thread::sleep(delay);
+        }
+    }
+
+    /// The hidden file next to `path` that ends in `suffix`, such as `.app.exe.autocopier.tmp`.
+    fn hidden_path(path: &Path, suffix: &str) -> PathBuf {
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        path.with_file_name(format!(".{}.{}", file_name, suffix))
+    }
+
+    /// The hidden file next to `path` that keeps its previous content while a group is swapped
+    /// in.
+    fn backup_path(path: &Path) -> PathBuf {
+        FileDescription::hidden_path(
+            path,
+            &format!("previous.{}", FileDescription::TEMPORARY_EXTENSION),
+        )
+    }
+
+    /// Stages a member of a group: the copy, and its checksum file next to it.
+    fn stage_member(&self) -> std::io::Result<Staged> {
+        if let Some(parent) = self.to.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let (temporary, digests) = self.stage()?;
+        let sidecar = match (self.options.write_checksum, &digests.sha256) {
+            (true, Some(sha256)) => {
+                let sidecar = FileDescription::hidden_path(
+                    &content::sidecar_path(&self.to),
+                    FileDescription::TEMPORARY_EXTENSION,
+                );
+                if let Err(e) = fs::write(&sidecar, content::sidecar_line(&self.to, sha256)) {
+                    let _ = fs::remove_file(&temporary);
+                    return Err(e);
+                }
+                Some(sidecar)
+            }
+            _ => None,
+        };
+        Ok(Staged {
+            temporary,
+            sidecar,
+            digests,
+        })
+    }
+
+    /// Renames `staged` over `path`, keeping what was there so it can be put back.
+    fn swap_in(staged: &Path, path: &Path, swapped: &mut Vec<Swapped>) -> std::io::Result<()> {
+        let backup = if path.exists() {
+            let backup = FileDescription::backup_path(path);
+            fs::rename(path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        if let Err(e) = fs::rename(staged, path) {
+            if let Some(backup) = &backup {
+                let _ = fs::rename(backup, path);
+            }
+            return Err(e);
+        }
+        swapped.push(Swapped {
+            path: path.to_path_buf(),
+            backup,
+        });
+        Ok(())
+    }
+
+    /// Writes the handoff marker of `to`, keeping a copy of the previous one so it can be put
+    /// back.
+    fn swap_marker(to: &Path, sha256: String, swapped: &mut Vec<Swapped>) -> std::io::Result<()> {
+        let marker = content::marker_path(to);
+        let backup = if marker.exists() {
+            let backup = FileDescription::backup_path(&marker);
+            fs::copy(&marker, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        swapped.push(Swapped {
+            path: marker,
+            backup,
+        });
+        content::write_marker(to, sha256)
+    }
+
+    /// Copies every member of a group, or none of them. The changed members are staged with
+    /// their checksum files first, and only swapped in once all of them are staged. When
+    /// swapping or writing a handoff marker fails, every file that was already swapped in gets
+    /// its previous content back.
+    pub fn copy_group(
+        members: &[FileDescription],
+        cache: &ContentCache,
+    ) -> Vec<std::io::Result<CopyOutcome>> {
+        let changed: Vec<bool> = members.iter().map(|m| !m.is_unchanged(cache)).collect();
+
+        let mut staged: Vec<(usize, Staged, u32)> = Vec::new();
+        let mut failure: Option<(usize, std::io::Error)> = None;
+        for (index, member) in members.iter().enumerate() {
+            if !changed[index] {
+                continue;
+            }
+            match member.with_retries(|| member.stage_member()) {
+                Ok((files, attempts)) => staged.push((index, files, attempts)),
+                Err(e) => {
+                    failure = Some((index, e));
+                    break;
+                }
+            }
+        }
+
+        // The checksum files go first, so they are already there when the next hop sees the
+        // files change, and the markers go last, once every file is in place.
+        let mut swapped: Vec<Swapped> = Vec::new();
+        if failure.is_none() {
+            for (index, files, _) in &staged {
+                let member = &members[*index];
+                let swap = (|| {
+                    if let Some(sidecar) = &files.sidecar {
+                        let path = content::sidecar_path(&member.to);
+                        FileDescription::swap_in(sidecar, &path, &mut swapped)?;
+                    }
+                    FileDescription::swap_in(&files.temporary, &member.to, &mut swapped)
+                })();
+                if let Err(e) = swap {
+                    failure = Some((*index, e));
+                    break;
+                }
+            }
+        }
+        if failure.is_none() {
+            for (index, files, _) in &staged {
+                let member = &members[*index];
+                if let (true, Some(sha256)) = (member.options.write_handoff, &files.digests.sha256)
+                {
+                    let result =
+                        FileDescription::swap_marker(&member.to, sha256.to_owned(), &mut swapped);
+                    if let Err(e) = result {
+                        failure = Some((*index, e));
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some((failed, e)) = failure {
+            // Roll back: put the previous content back, and throw away what was staged.
+            for file in swapped.into_iter().rev() {
+                match file.backup {
+                    Some(backup) => {
+                        let _ = fs::rename(backup, &file.path);
+                    }
+                    None => {
+                        let _ = fs::remove_file(&file.path);
+                    }
+                }
+            }
+            for (_, files, _) in &staged {
+                let _ = fs::remove_file(&files.temporary);
+                if let Some(sidecar) = &files.sidecar {
+                    let _ = fs::remove_file(sidecar);
+                }
+            }
+            let message = format!(
+                "the group was rolled back, because {:?} failed: {}",
+                members[failed].from, e
+            );
+            return members
+                .iter()
+                .enumerate()
+                .map(|(index, _)| {
+                    if index == failed {
+                        Err(std::io::Error::new(e.kind(), e.to_string()))
+                    } else if changed[index] {
+                        Err(std::io::Error::new(e.kind(), message.clone()))
+                    } else {
+                        Ok(CopyOutcome::Skipped)
+                    }
+                })
+                .collect();
+        }
+
+        for file in swapped {
+            if let Some(backup) = file.backup {
+                let _ = fs::remove_file(backup);
+            }
+        }
+        let mut outcomes: Vec<std::io::Result<CopyOutcome>> =
+            members.iter().map(|_| Ok(CopyOutcome::Skipped)).collect();
+        for (index, files, attempts) in staged {
+            let member = &members[index];
+            if member.options.skip_unchanged {
+                cache.copied(&member.to, files.digests.blake3);
+            }
+            outcomes[index] = Ok(CopyOutcome::Copied(attempts));
+        }
+        outcomes
+    }
+}
+
+/// The files of a group member that are staged next to its destination.
+struct Staged {
+    temporary: PathBuf,
+    /// The checksum file, if the rule writes one.
+    sidecar: Option<PathBuf>,
+    digests: Digests,
+}
+
+/// A file of a group that was swapped in, with the backup of what was there before.
+struct Swapped {
+    path: PathBuf,
+    backup: Option<PathBuf>,
+}
+
+impl FileDescription {
+    /// Handles the destination according to the rule, because the source was removed. The
+    /// checksum file and handoff marker next to it go as well.
+    pub fn remove_destination(&self) -> std::io::Result<Removal> {
+        if self.options.on_remove == RemovePolicy::Keep || !self.to.exists() {
+            return Ok(Removal::Kept);
+        }
+        if self.to.is_file() && (self.options.write_checksum || self.options.write_handoff) {
+            for companion in &content::companions(&self.to) {
+                let _ = fs::remove_file(companion);
+            }
+        }
+        match &self.options.on_remove {
+            RemovePolicy::Keep => Ok(Removal::Kept),
+            RemovePolicy::Delete => {
+                if self.to.is_dir() {
+                    fs::remove_dir_all(&self.to)?;
+                } else {
+                    fs::remove_file(&self.to)?;
+                }
+                Ok(Removal::Deleted)
+            }
+            RemovePolicy::Quarantine(directory) => {
+                let file_name = self.to.file_name().unwrap_or_default().to_string_lossy();
+                let quarantined = directory.join(format!(
+                    "{}.{}",
+                    file_name,
+                    chrono::Local::now().format("%Y%m%d-%H%M%S%.3f")
+                ));
+                fs::create_dir_all(directory)?;
+                if fs::rename(&self.to, &quarantined).is_err() {
+                    // The quarantine may be on another device.
+                    copy_recursively(&self.to, &quarantined)?;
+                    if self.to.is_dir() {
+                        fs::remove_dir_all(&self.to)?;
+                    } else {
+                        fs::remove_file(&self.to)?;
+                    }
+                }
+                Ok(Removal::Quarantined(quarantined))
+            }
+        }
+    }
+
+    /// Moves the destination of `old` to the destination of this description, because the source
+    /// was renamed from `old`. The checksum file and handoff marker are written anew.
+    pub fn take_over(&self, old: &FileDescription) -> std::io::Result<()> {
+        if let Some(parent) = self.to.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        fs::rename(&old.to, &self.to)?;
+        if !self.to.is_file() {
+            return Ok(());
+        }
+        for companion in &content::companions(&old.to) {
+            let _ = fs::remove_file(companion);
+        }
+        if self.options.write_checksum || self.options.write_handoff {
+            let sha256 = content::checksum(&self.to, HashAlgorithm::Sha256)?;
+            if self.options.write_checksum {
+                content::write_sidecar(&self.to, &sha256)?;
+            }
+            if self.options.write_handoff {
+                content::write_marker(&self.to, sha256)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copies the file or the directory tree at `from` to `to`.
+fn copy_recursively(from: &Path, to: &Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+impl Clone for FileDescription {
+    fn clone(&self) -> FileDescription {
+        FileDescription {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_remove_policies() {
+        let base = TempDir::new("remove_policies");
+        fs::create_dir_all(base.join("share")).unwrap();
+        fs::write(base.join("share").join("app.exe"), b"Old").unwrap();
+
+        let mut fd = FileDescription {
+            from: base.join("app.exe"),
+            to: base.join("share").join("app.exe"),
+            options: CopyOptions::default(),
+        };
+        assert_eq!(Removal::Kept, fd.remove_destination().unwrap());
+        assert!(fd.to.exists());
+
+        fd.options.on_remove = RemovePolicy::Quarantine(base.join("quarantine"));
+        let quarantined = match fd.remove_destination().unwrap() {
+            Removal::Quarantined(v) => v,
+            other => panic!("{:?}", other),
+        };
+        assert!(!fd.to.exists());
+        assert!(quarantined.starts_with(base.join("quarantine")));
+        assert_eq!(fs::read(&quarantined).unwrap(), b"Old");
+
+        fs::write(&fd.to, b"Old").unwrap();
+        fd.options.on_remove = RemovePolicy::Delete;
+        assert_eq!(Removal::Deleted, fd.remove_destination().unwrap());
+        assert!(!fd.to.exists());
+        // Nothing left to remove.
+        assert_eq!(Removal::Kept, fd.remove_destination().unwrap());
+
+        fs::write(&fd.to, b"Old").unwrap();
+        let renamed = FileDescription {
+            from: base.join("new.exe"),
+            to: base.join("share").join("sub").join("new.exe"),
+            options: CopyOptions::default(),
+        };
+        renamed.take_over(&fd).unwrap();
+        assert!(!fd.to.exists());
+        assert_eq!(fs::read(&renamed.to).unwrap(), b"Old");
+
+        // What a quarantine on another device falls back to.
+        copy_recursively(&base.join("share"), &base.join("copied")).unwrap();
+        assert_eq!(
+            fs::read(base.join("copied").join("sub").join("new.exe")).unwrap(),
+            b"Old"
+        );
+    }
+
+    #[test]
+    fn test_atomic_copy() {
+        let base = TempDir::new("atomic_copy");
+        fs::write(base.join("app.exe"), b"New").unwrap();
+        fs::create_dir_all(base.join("share")).unwrap();
+        fs::write(base.join("share").join("app.exe"), b"Old").unwrap();
+
+        let mut fd = FileDescription {
+            from: base.join("app.exe"),
+            to: base.join("share").join("app.exe"),
+            options: CopyOptions::default(),
+        };
+        assert!(FileDescription::is_temporary(fd.temporary_path()));
+        assert!(!FileDescription::is_temporary(&fd.to));
+
+        assert_eq!(fd.copy().unwrap(), CopyOutcome::Copied(1));
+        assert_eq!(fd.copy().unwrap(), CopyOutcome::Skipped);
+        assert_eq!(fs::read(&fd.to).unwrap(), b"New");
+        assert!(!fd.temporary_path().exists());
+
+        fd.options.atomic = false;
+        fd.to = base.join("plain").join("app.exe");
+        fd.copy().unwrap();
+        assert_eq!(fs::read(&fd.to).unwrap(), b"New");
+    }
+
+    #[test]
+    fn test_copy_group() {
+        let base = TempDir::new("copy_group");
+        fs::create_dir_all(base.join("share")).unwrap();
+        fs::write(base.join("app.exe"), b"New").unwrap();
+        fs::write(base.join("app.pdb"), b"New").unwrap();
+        fs::write(base.join("share").join("app.exe"), b"Old").unwrap();
+        fs::write(base.join("share").join("app.pdb"), b"Old").unwrap();
+
+        let options = CopyOptions {
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            group: Some(Group::Named(String::from("app"))),
+            ..CopyOptions::default()
+        };
+        let members: Vec<FileDescription> = ["app.exe", "app.pdb"]
+            .iter()
+            .map(|name| FileDescription {
+                from: base.join(name),
+                to: base.join("share").join(name),
+                options: options.clone(),
+            })
+            .collect();
+        let cache = ContentCache::default();
+
+        // One of the sources is gone, so none of the destinations change.
+        fs::remove_file(base.join("app.pdb")).unwrap();
+        let results = FileDescription::copy_group(&members, &cache);
+        assert!(results.iter().all(|r| r.is_err()));
+        assert_eq!(
+            fs::read(base.join("share").join("app.exe")).unwrap(),
+            b"Old"
+        );
+        assert_eq!(
+            fs::read(base.join("share").join("app.pdb")).unwrap(),
+            b"Old"
+        );
+        assert_eq!(2, fs::read_dir(base.join("share")).unwrap().count());
+
+        fs::write(base.join("app.pdb"), b"New").unwrap();
+        let results = FileDescription::copy_group(&members, &cache);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Ok(CopyOutcome::Copied(1)))));
+        assert_eq!(
+            fs::read(base.join("share").join("app.exe")).unwrap(),
+            b"New"
+        );
+        assert_eq!(
+            fs::read(base.join("share").join("app.pdb")).unwrap(),
+            b"New"
+        );
+        assert_eq!(2, fs::read_dir(base.join("share")).unwrap().count());
+
+        let results = FileDescription::copy_group(&members, &cache);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Ok(CopyOutcome::Skipped))));
+
+        // The marker of app.pdb can't be written, so app.exe gets its previous content, checksum
+        // file and marker back.
+        let share = base.join("share");
+        fs::write(base.join("app.exe"), b"Newer").unwrap();
+        fs::write(base.join("app.pdb"), b"Newer").unwrap();
+        fs::write(share.join("app.exe.sha256"), b"previous").unwrap();
+        fs::write(share.join("app.exe.handoff"), b"previous").unwrap();
+        fs::create_dir_all(share.join("app.pdb.handoff").join("blocked")).unwrap();
+        let members: Vec<FileDescription> = members
+            .into_iter()
+            .map(|mut member| {
+                member.options.write_checksum = true;
+                member.options.write_handoff = true;
+                member
+            })
+            .collect();
+        let results = FileDescription::copy_group(&members, &cache);
+        assert!(results.iter().all(|r| r.is_err()));
+        assert_eq!(fs::read(share.join("app.exe")).unwrap(), b"New");
+        assert_eq!(fs::read(share.join("app.pdb")).unwrap(), b"New");
+        assert_eq!(fs::read(share.join("app.exe.sha256")).unwrap(), b"previous");
+        assert_eq!(
+            fs::read(share.join("app.exe.handoff")).unwrap(),
+            b"previous"
+        );
+        assert!(!share.join("app.pdb.sha256").exists());
+        assert_eq!(5, fs::read_dir(&share).unwrap().count());
+
+        fs::remove_dir_all(share.join("app.pdb.handoff")).unwrap();
+        let results = FileDescription::copy_group(&members, &cache);
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(fs::read(share.join("app.pdb")).unwrap(), b"Newer");
+        content::check_sidecar(&share.join("app.exe")).unwrap();
+        content::check_sidecar(&share.join("app.pdb")).unwrap();
+        assert_eq!(
+            1,
+            content::read_marker(&share.join("app.pdb"))
+                .unwrap()
+                .generation
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let mut retry = RetryPolicy {
+            max_attempts: 4,
+            backoff: Backoff::Exponential,
+            delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(30),
+            timeout: None,
+        };
+        assert_eq!(Duration::from_millis(10), retry.delay_after(1));
+        assert_eq!(Duration::from_millis(20), retry.delay_after(2));
+        assert_eq!(Duration::from_millis(30), retry.delay_after(3));
+        assert_eq!(Duration::from_millis(30), retry.delay_after(40));
+
+        let base = TempDir::new("retry_policy");
+        let mut fd = FileDescription {
+            from: base.join("missing.exe"),
+            to: base.join("share").join("missing.exe"),
+            options: CopyOptions {
+                retry: retry.clone(),
+                ..CopyOptions::default()
+            },
+        };
+        let e = fd.copy().unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, e.kind());
+        assert!(e.to_string().starts_with("gave up after 4 attempt(s)"));
+
+        retry.backoff = Backoff::Fixed;
+        retry.max_attempts = 1000;
+        retry.timeout = Some(Duration::from_millis(50));
+        fd.options.retry = retry;
+        let started = Instant::now();
+        assert!(fd.copy().is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_checksums() {
+        let base = TempDir::new("checksums");
+        fs::write(base.join("app.exe"), b"App").unwrap();
+
+        let start = FileDescription {
+            from: base.join("app.exe"),
+            to: base.join("share").join("app.exe"),
+            options: CopyOptions {
+                verify: Some(HashAlgorithm::Sha256),
+                write_checksum: true,
+                write_handoff: true,
+                ..CopyOptions::default()
+            },
+        };
+        start.copy().unwrap();
+        assert!(base.join("share").join("app.exe.sha256").exists());
+        let marker = content::read_marker(&base.join("share").join("app.exe")).unwrap();
+        assert_eq!(1, marker.generation);
+
+        let end = FileDescription {
+            from: base.join("share").join("app.exe"),
+            to: base.join("rig").join("app.exe"),
+            options: CopyOptions {
+                retry: RetryPolicy {
+                    max_attempts: 2,
+                    ..RetryPolicy::default()
+                },
+                check_checksum: true,
+                check_handoff: true,
+                ..CopyOptions::default()
+            },
+        };
+        end.copy().unwrap();
+        assert_eq!(fs::read(base.join("rig").join("app.exe")).unwrap(), b"App");
+
+        // Corrupted on the share.
+        fs::write(base.join("share").join("app.exe"), b"Apq").unwrap();
+        let e = end.copy().unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, e.kind());
+        assert_eq!(fs::read(base.join("rig").join("app.exe")).unwrap(), b"App");
+    }
+}
@@ -1,63 +1,71 @@
 use notify::DebouncedEvent::*;
-use notify::RecursiveMode::Recursive;
-use notify::{
-    watcher, DebouncedEvent, PollWatcher, ReadDirectoryChangesWatcher, RecursiveMode, Watcher,
-};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
-use std::sync::RwLock;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
-use std::{fs, thread};
 
-use crate::FileDescription;
+use crate::configuration_reader::Configuration;
 
 mod my_watcher;
 pub mod notifications;
+mod paths;
+mod scheduler;
+mod watch_state;
 
 use my_watcher::MyWatcher;
 pub use notifications::{Notification, UiNotification};
+pub use scheduler::CopyLimits;
+use watch_state::WatchState;
+
+/// How often the sources that are waiting to settle are looked at.
+const SETTLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Parses the configuration file again, after it changed.
+pub type ConfigurationParser = Box<dyn Fn() -> Result<Configuration, Error> + Send>;
 
 pub struct FileWatcher {
     should_run_tx: Sender<Notification>,
     should_run_rx: Option<Receiver<Notification>>,
-    file_descriptions_map: HashMap<PathBuf, FileDescription>,
+    configuration: Option<Configuration>,
+    configuration_file: Option<(PathBuf, ConfigurationParser)>,
     ui_notification_transmitters: Vec<Sender<UiNotification>>,
     join_handle: Option<JoinHandle<()>>,
     use_polling: bool,
+    copy_limits: CopyLimits,
 }
 
 impl FileWatcher {
-    pub fn new(file_descriptions: Vec<FileDescription>, use_polling: bool) -> FileWatcher {
-        let mut map: HashMap<PathBuf, FileDescription> = HashMap::new();
-        for fd in file_descriptions {
-            map.insert(fd.from.clone(), fd);
-        }
+    pub fn new(configuration: Configuration, use_polling: bool) -> FileWatcher {
         let (tx, rx): (Sender<Notification>, Receiver<Notification>) = channel();
         FileWatcher {
-            file_descriptions_map: map,
+            configuration: Some(configuration),
+            configuration_file: None,
             join_handle: None,
             should_run_tx: tx,
             should_run_rx: Some(rx),
             ui_notification_transmitters: Vec::new(),
-            use_polling: use_polling,
+            use_polling,
+            copy_limits: CopyLimits::default(),
         }
     }
 
-    pub fn stop(mut self) {
-        match self.should_run_tx.send(Notification::B(false)) {
-            Ok(_) => {}
-            Err(e) => {}
-        }
-        match self.join_handle {
-            Some(jh) => {
-                self.join_handle = None;
-                println!("Waiting for join handle");
-                jh.join();
-            }
-            None => {}
+    /// Sets how many copies run at the same time. Must be called before `start`.
+    pub fn set_copy_limits(&mut self, copy_limits: CopyLimits) {
+        self.copy_limits = copy_limits;
+    }
+
+    /// Reloads the configuration with `parser` whenever the file at `path` changes. Must be
+    /// called before `start`.
+    pub fn watch_configuration(&mut self, path: PathBuf, parser: ConfigurationParser) {
+        self.configuration_file = Some((MyWatcher::transform_path(path), parser));
+    }
+
+    pub fn stop(self) {
+        let _ = self.should_run_tx.send(Notification::B(false));
+        if let Some(jh) = self.join_handle {
+            let _ = jh.join();
         }
     }
 
@@ -67,138 +75,785 @@ impl FileWatcher {
         rx
     }
 
-    fn send_ui_notification(
-        transmitters: &Vec<Sender<UiNotification>>,
-        notification: UiNotification,
-    ) {
-        //println!("Sending a notification");
+    /// Sends `message` to every UI, for the threads that don't have a `WatchState` to do so.
+    fn send_warning(transmitters: &[Sender<UiNotification>], message: String) {
         for tx in transmitters {
-            tx.send(notification.clone());
+            let _ = tx.send(UiNotification::Warning(message.clone()));
         }
     }
 
+    /// Watches the directory of the configuration file, since editors often replace the file
+    /// instead of writing to it, and sends the parsed configuration after every change.
+    fn start_configuration_watcher(
+        path: PathBuf,
+        parser: ConfigurationParser,
+        notification_tx: Sender<Notification>,
+        transmitters: Vec<Sender<UiNotification>>,
+        use_polling: bool,
+    ) {
+        thread::spawn(move || {
+            let (tx, rx) = channel();
+            let mut watch = match MyWatcher::get_watcher(tx, use_polling) {
+                Ok(v) => v,
+                Err(e) => {
+                    FileWatcher::send_warning(
+                        &transmitters,
+                        format!(
+                            "Couldn't watch the configuration {:?}, because: {}",
+                            path, e
+                        ),
+                    );
+                    return;
+                }
+            };
+            let directory = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+            if let Err(e) = watch.watch(&directory, notify::RecursiveMode::NonRecursive) {
+                FileWatcher::send_warning(
+                    &transmitters,
+                    format!(
+                        "Couldn't watch the configuration {:?}, because: {}",
+                        path, e
+                    ),
+                );
+                return;
+            }
+
+            while let Ok(event) = rx.recv() {
+                let changed = match &event {
+                    NoticeWrite(p) | Write(p) | Create(p) | Rename(_, p) => *p == path,
+                    _ => false,
+                };
+                if !changed {
+                    continue;
+                }
+                // Saving a file usually takes a few events, only reload once they are done.
+                loop {
+                    match rx.recv_timeout(Duration::from_millis(200)) {
+                        Ok(_) => {}
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                let result = parser().map_err(|e| e.to_string());
+                if notification_tx.send(Notification::Reload(result)).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
     pub fn start(mut self) -> FileWatcher {
-        let mut file_descriptions_map_clone: HashMap<PathBuf, FileDescription> = HashMap::new();
-        for fd in self.file_descriptions_map.values() {
-            file_descriptions_map_clone.insert(fd.from.clone(), fd.clone());
-        }
+        let configuration = self.configuration.take().unwrap();
 
         let (tx, rx) = channel();
 
         let tx_pass_through = self.should_run_tx.clone();
-        let rx_combined = self.should_run_rx.unwrap();
-        self.should_run_rx = None;
+        let rx_combined = self.should_run_rx.take().unwrap();
 
         // thread to pack the debounced event.
-        thread::spawn(move || loop {
-            match rx.recv() {
-                Ok(v) => {
-                    tx_pass_through.send(Notification::E(v));
+        thread::spawn(move || {
+            while let Ok(v) = rx.recv() {
+                if tx_pass_through.send(Notification::E(v)).is_err() {
+                    break;
                 }
-                Err(e) => {}
             }
         });
 
-        let transmitters: Vec<Sender<UiNotification>> =
-            self.ui_notification_transmitters.iter().cloned().collect();
+        let transmitters: Vec<Sender<UiNotification>> = self.ui_notification_transmitters.to_vec();
 
-        let use_polling = self.use_polling.clone();
+        let use_polling = self.use_polling;
+        let copy_limits = self.copy_limits;
 
-        let jh: JoinHandle<()> = thread::spawn(move || {
-            // Create a channel to receive the events.
+        if let Some((path, parser)) = self.configuration_file.take() {
+            FileWatcher::start_configuration_watcher(
+                path,
+                parser,
+                self.should_run_tx.clone(),
+                transmitters.clone(),
+                use_polling,
+            );
+        }
 
+        let jh: JoinHandle<()> = thread::spawn(move || {
             // Automatically select the best implementation for your platform.
-            // You can also access each implementation directly e.g. INotifyWatcher.
-
-            let mut watch = match MyWatcher::get_watcher(tx, use_polling) {
+            let watch = match MyWatcher::get_watcher(tx, use_polling) {
                 Ok(v) => v,
                 Err(e) => {
+                    FileWatcher::send_warning(
+                        &transmitters,
+                        format!("Couldn't start watching, because: {}", e),
+                    );
                     return;
                 }
             };
-            FileWatcher::send_ui_notification(&transmitters, UiNotification::Started);
-
-            let mut watched_files: Vec<PathBuf> = Vec::new();
-
-            for fd in file_descriptions_map_clone.values() {
-                let from = fd.from.clone();
-                match watch.watch(&from, RecursiveMode::NonRecursive) {
-                    Ok(_) => {
-                        watched_files.push(from.clone());
-                        FileWatcher::send_ui_notification(
-                            &transmitters,
-                            UiNotification::StartedWatching(from.clone()),
-                        );
-                    }
-                    Err(e) => {
-                        println!("Couldn't watch file: {:?} because {:?}", "", e);
-                    }
-                };
-            }
+            let mut state = WatchState::new(watch, transmitters, copy_limits);
+            state.send_ui_notification(UiNotification::Started);
+            state.apply(configuration);
 
             loop {
-                // Receive
-                match rx_combined.recv() {
-                    Ok(event) => match event {
-                        Notification::E(ev) => match ev {
-                            NoticeWrite(p) => {
-                                //println!("{:?}", p);
-                                match file_descriptions_map_clone.get(&p) {
-                                    Some(fd) => {
-                                        //println!("Found key!");
-                                        // Notify
-                                        FileWatcher::send_ui_notification(
-                                            &transmitters,
-                                            UiNotification::Copied(fd.from.clone(), fd.to.clone()),
-                                        );
-                                        fd.copy();
-                                    }
-                                    None => {
-                                        println!("Could not find key");
-                                    }
-                                }
-                                /*
-                                for pb in file_descriptions_map_clone.keys() {
-                                    println!("{:?}", pb);
-                                }
-                                */
-                            }
-                            _ => {}
-                        },
-                        Notification::B(b) => {
-                            if b == false {
-                                //println!("Started with unwatching");
-                                for p in watched_files {
-                                    //println!("Unwatching: {:?}", &p);
-                                    let p_clone = p.clone();
-                                    match watch.unwatch(p) {
-                                        Ok(_) => {
-                                            //println!("unwatched");
-                                            FileWatcher::send_ui_notification(
-                                                &transmitters,
-                                                UiNotification::StoppedWatching(p_clone),
-                                            );
-                                        }
-                                        Err(e) => {
-                                            println!(
-                                                "Could not stop watching {:?} because {:?}",
-                                                "", e
-                                            );
-                                        }
-                                    };
-                                }
-                                break;
-                            }
+                // Only wake up regularly while there are copies waiting for their source to
+                // settle.
+                let event = if state.has_pending() {
+                    match rx_combined.recv_timeout(SETTLE_POLL_INTERVAL) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => {
+                            state.poll_pending();
+                            continue;
                         }
-                    },
-                    Err(e) => {
-                        //Disconnected
-                        //println!("watch error: {:?}", e);
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                } else {
+                    match rx_combined.recv() {
+                        Ok(event) => event,
+                        Err(_) => break,
+                    }
+                };
+                match event {
+                    Notification::E(ev) => state.handle_event(ev),
+                    Notification::Reload(Ok(configuration)) => {
+                        state.apply(configuration);
+                        state.send_ui_notification(UiNotification::ConfigurationReloaded);
+                    }
+                    Notification::Reload(Err(e)) => {
+                        // Keep watching with the configuration we have.
+                        state.send_ui_notification(UiNotification::ConfigurationError(e));
                     }
+                    Notification::B(false) => {
+                        state.unwatch_all();
+                        break;
+                    }
+                    Notification::B(true) => {}
                 }
+                state.poll_pending();
             }
         });
         self.join_handle = Some(jh);
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::{
+        CopyOptions, FileDescription, Group, RemovePolicy, RenamePolicy, RetryPolicy, Settle,
+    };
+    use crate::rules::{DirectoryDescription, GlobDescription};
+    use crate::test_support::{has_contents, wait_until, TempDir, TIMEOUT};
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Component;
+    use std::time::Duration;
+
+    /// Starts `file_watcher` and waits until it watches everything in its configuration. It
+    /// reports the watches as it sets them up, so that is once it stops reporting anything.
+    fn start(mut file_watcher: FileWatcher) -> FileWatcher {
+        let rx = file_watcher.get_ui_notification_receiver();
+        let file_watcher = file_watcher.start();
+        assert!(matches!(
+            rx.recv_timeout(TIMEOUT),
+            Ok(UiNotification::Started)
+        ));
+        while rx.recv_timeout(Duration::from_millis(200)).is_ok() {}
+        file_watcher
+    }
+
+    /// Gives the watcher time to react to a change it should ignore, or one it can't report.
+    fn pause() {
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_glob_copies_new_files() {
+        let base = TempDir::new("file_watcher_glob");
+        fs::create_dir_all(base.join("bin")).unwrap();
+
+        let glob = GlobDescription::new(
+            &base.join("bin").join("**").join("*.dll").to_string_lossy(),
+            &base
+                .join("share")
+                .join(GlobDescription::RELPATH)
+                .to_string_lossy(),
+        )
+        .unwrap();
+        let configuration = Configuration {
+            files: Vec::new(),
+            globs: vec![glob],
+            directories: Vec::new(),
+        };
+        let mut file_watcher = FileWatcher::new(configuration, false);
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+        assert!(matches!(rx.try_recv(), Ok(UiNotification::Started)));
+
+        fs::create_dir_all(base.join("bin").join("nested")).unwrap();
+        let mut f = File::create(base.join("bin").join("nested").join("new.dll")).unwrap();
+        f.write_all(b"Test").unwrap();
+        drop(f);
+        File::create(base.join("bin").join("new.txt")).unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("nested").join("new.dll"), b"Test")
+        });
+        pause();
+        file_watcher.stop();
+
+        assert!(!base.join("share").join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_directory_mirrors_changes() {
+        let base = TempDir::new("file_watcher_directory");
+        fs::create_dir_all(base.join("bin")).unwrap();
+        fs::write(base.join("bin").join("app.dll"), b"Old").unwrap();
+
+        let configuration = Configuration {
+            files: Vec::new(),
+            globs: Vec::new(),
+            directories: vec![DirectoryDescription {
+                from: base.join("bin"),
+                to: base.join("share"),
+                include: Vec::new(),
+                exclude: Vec::new(),
+                delete_extraneous: false,
+                options: CopyOptions {
+                    on_remove: RemovePolicy::Delete,
+                    on_rename: RenamePolicy::Rename,
+                    ..CopyOptions::default()
+                },
+            }],
+        };
+        let file_watcher = start(FileWatcher::new(configuration, false));
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("app.dll"), b"Old")
+        });
+
+        fs::write(base.join("bin").join("app.dll"), b"New").unwrap();
+        fs::create_dir_all(base.join("bin").join("nested")).unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("app.dll"), b"New")
+        });
+        fs::write(base.join("bin").join("nested").join("lib.dll"), b"Lib").unwrap();
+        fs::write(base.join("bin").join("temporary.dll"), b"Temporary").unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("nested").join("lib.dll"), b"Lib")
+                && has_contents(&base.join("share").join("temporary.dll"), b"Temporary")
+        });
+
+        fs::rename(
+            base.join("bin").join("nested").join("lib.dll"),
+            base.join("bin").join("nested").join("renamed.dll"),
+        )
+        .unwrap();
+        fs::remove_file(base.join("bin").join("temporary.dll")).unwrap();
+        wait_until(TIMEOUT, || {
+            !base.join("share").join("nested").join("lib.dll").exists()
+                && !base.join("share").join("temporary.dll").exists()
+        });
+        file_watcher.stop();
+
+        assert_eq!(
+            fs::read(base.join("share").join("nested").join("renamed.dll")).unwrap(),
+            b"Lib"
+        );
+    }
+
+    #[test]
+    fn test_reloads_configuration() {
+        let base = TempDir::new("file_watcher_reload");
+        let configuration_file = base.join("configuration.json");
+        let write_configuration = |names: &[&str]| {
+            let files: Vec<String> = names
+                .iter()
+                .map(|name| {
+                    format!(
+                        "{{\"from\": {:?}, \"through\": {:?}, \"to\": {:?}}}",
+                        base.join("from").join(name),
+                        base.join("through").join(name),
+                        base.join("to").join(name)
+                    )
+                })
+                .collect();
+            fs::write(
+                &configuration_file,
+                format!("{{\"aliases\": [], \"files\": [{}]}}", files.join(", ")),
+            )
+            .unwrap();
+        };
+        fs::create_dir_all(base.join("from")).unwrap();
+        fs::write(base.join("from").join("a.dll"), b"A").unwrap();
+        fs::write(base.join("from").join("b.dll"), b"B").unwrap();
+        write_configuration(&["a.dll"]);
+
+        let parse = {
+            let configuration_file = configuration_file.clone();
+            move || {
+                crate::configuration_reader::parse_configuration(
+                    configuration_file.to_str().unwrap(),
+                    None,
+                    &crate::StepInChain::Start,
+                    &[],
+                )
+            }
+        };
+        let mut file_watcher = FileWatcher::new(parse().unwrap(), false);
+        file_watcher.watch_configuration(configuration_file.clone(), Box::new(parse));
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+
+        write_configuration(&["b.dll"]);
+        let mut notifications: Vec<UiNotification> = Vec::new();
+        wait_until(TIMEOUT, || {
+            notifications.extend(rx.try_iter());
+            notifications
+                .iter()
+                .any(|n| matches!(n, UiNotification::ConfigurationReloaded))
+        });
+        fs::write(base.join("from").join("a.dll"), b"A2").unwrap();
+        fs::write(base.join("from").join("b.dll"), b"B2").unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("through").join("b.dll"), b"B2")
+        });
+
+        fs::write(&configuration_file, b"{").unwrap();
+        wait_until(TIMEOUT, || {
+            notifications.extend(rx.try_iter());
+            notifications
+                .iter()
+                .any(|n| matches!(n, UiNotification::ConfigurationError(_)))
+        });
+        file_watcher.stop();
+
+        assert!(!base.join("through").join("a.dll").exists());
+    }
+
+    #[test]
+    fn test_replaced_file_is_watched_again() {
+        let base = TempDir::new("file_watcher_replaced");
+        fs::write(base.join("app.exe"), b"Old").unwrap();
+
+        let configuration = Configuration {
+            files: vec![FileDescription {
+                from: base.join("app.exe"),
+                to: base.join("share").join("app.exe"),
+                // Otherwise the write below can be copied while it is still truncated.
+                options: CopyOptions {
+                    settle: Settle::Stable(Duration::from_millis(100)),
+                    ..CopyOptions::default()
+                },
+            }],
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        let file_watcher = start(FileWatcher::new(configuration, false));
+
+        // Replaced the way an atomic copy does.
+        fs::write(base.join(".app.exe.autocopier.tmp"), b"Replaced").unwrap();
+        fs::rename(base.join(".app.exe.autocopier.tmp"), base.join("app.exe")).unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("app.exe"), b"Replaced")
+        });
+
+        fs::write(base.join("app.exe"), b"Written").unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("app.exe"), b"Written")
+        });
+        file_watcher.stop();
+    }
+
+    #[test]
+    fn test_settle_coalesces_changes() {
+        let base = TempDir::new("file_watcher_settle");
+        fs::write(base.join("app.pdb"), b"").unwrap();
+
+        let configuration = Configuration {
+            files: vec![FileDescription {
+                from: base.join("app.pdb"),
+                to: base.join("share").join("app.pdb"),
+                options: CopyOptions {
+                    settle: Settle::Stable(Duration::from_millis(400)),
+                    ..CopyOptions::default()
+                },
+            }],
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        let mut file_watcher = FileWatcher::new(configuration, false);
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+
+        // Written in parts, like a linker does.
+        let mut f = fs::OpenOptions::new()
+            .append(true)
+            .open(base.join("app.pdb"))
+            .unwrap();
+        for _ in 0..5 {
+            f.write_all(b"Part").unwrap();
+            f.sync_all().unwrap();
+            thread::sleep(Duration::from_millis(150));
+        }
+        drop(f);
+        let mut copies = 0;
+        wait_until(TIMEOUT, || {
+            copies += rx
+                .try_iter()
+                .filter(|n| matches!(n, UiNotification::Copied(_, _)))
+                .count();
+            copies > 0
+        });
+        pause();
+        file_watcher.stop();
+
+        assert_eq!(
+            fs::read(base.join("share").join("app.pdb")).unwrap(),
+            b"PartPartPartPartPart"
+        );
+        copies += rx
+            .try_iter()
+            .filter(|n| matches!(n, UiNotification::Copied(_, _)))
+            .count();
+        assert_eq!(1, copies);
+    }
+
+    #[test]
+    fn test_handoff() {
+        let base = TempDir::new("file_watcher_handoff");
+        fs::create_dir_all(base.join("share")).unwrap();
+        let through = base.join("share").join("app.exe");
+        let to = base.join("rig").join("app.exe");
+        let mark = |path: &std::path::Path| {
+            let sha256 =
+                crate::content::checksum(path, crate::content::HashAlgorithm::Sha256).unwrap();
+            crate::content::write_marker(path, sha256).unwrap();
+        };
+
+        let configuration = Configuration {
+            files: vec![FileDescription {
+                from: through.clone(),
+                to: to.clone(),
+                options: CopyOptions {
+                    check_handoff: true,
+                    ..CopyOptions::default()
+                },
+            }],
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        // Handed off before watching started.
+        fs::write(&through, b"Zero").unwrap();
+        mark(&through);
+        let file_watcher = start(FileWatcher::new(configuration, false));
+        wait_until(TIMEOUT, || has_contents(&to, b"Zero"));
+
+        // Without a new marker, the file isn't complete yet.
+        fs::write(&through, b"First").unwrap();
+        pause();
+        assert_eq!(fs::read(&to).unwrap(), b"Zero");
+
+        mark(&through);
+        wait_until(TIMEOUT, || has_contents(&to, b"First"));
+
+        // The next generation is still being written.
+        fs::write(&through, b"Sec").unwrap();
+        pause();
+        assert_eq!(fs::read(&to).unwrap(), b"First");
+
+        fs::write(&through, b"Second").unwrap();
+        mark(&through);
+        wait_until(TIMEOUT, || has_contents(&to, b"Second"));
+        file_watcher.stop();
+    }
+
+    #[test]
+    fn test_group_waits_for_all_files() {
+        let base = TempDir::new("file_watcher_group");
+        fs::write(base.join("app.exe"), b"").unwrap();
+        fs::write(base.join("app.pdb"), b"").unwrap();
+
+        let files = ["app.exe", "app.pdb"]
+            .iter()
+            .map(|name| FileDescription {
+                from: base.join(name),
+                to: base.join("share").join(name),
+                options: CopyOptions {
+                    group: Some(Group::Named(String::from("app"))),
+                    ..CopyOptions::default()
+                },
+            })
+            .collect();
+        let configuration = Configuration {
+            files,
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        let mut file_watcher = FileWatcher::new(configuration, false);
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+
+        // The exe is written first, the pdb follows before the group has settled.
+        fs::write(base.join("app.exe"), b"New").unwrap();
+        thread::sleep(Duration::from_millis(250));
+        assert!(!base.join("share").join("app.exe").exists());
+        fs::write(base.join("app.pdb"), b"New").unwrap();
+        let mut copies = 0;
+        wait_until(TIMEOUT, || {
+            copies += rx
+                .try_iter()
+                .filter(|n| matches!(n, UiNotification::Copied(_, _)))
+                .count();
+            copies >= 2
+        });
+        file_watcher.stop();
+
+        assert_eq!(
+            fs::read(base.join("share").join("app.exe")).unwrap(),
+            b"New"
+        );
+        assert_eq!(
+            fs::read(base.join("share").join("app.pdb")).unwrap(),
+            b"New"
+        );
+        assert_eq!(2, copies);
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let base = TempDir::new("file_watcher_fan_out");
+        fs::write(base.join("app.exe"), b"").unwrap();
+        // The second rig can't be copied to, because its directory is a file.
+        fs::write(base.join("rig2"), b"").unwrap();
+
+        let files = ["share", "rig1", "rig2"]
+            .iter()
+            .map(|destination| FileDescription {
+                from: base.join("app.exe"),
+                to: base.join(destination).join("app.exe"),
+                options: CopyOptions {
+                    retry: RetryPolicy {
+                        max_attempts: 2,
+                        ..RetryPolicy::default()
+                    },
+                    ..CopyOptions::default()
+                },
+            })
+            .collect();
+        let configuration = Configuration {
+            files,
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        let mut file_watcher = FileWatcher::new(configuration, false);
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+
+        fs::write(base.join("app.exe"), b"New").unwrap();
+        let mut copied: Vec<PathBuf> = Vec::new();
+        let mut failed: Vec<PathBuf> = Vec::new();
+        wait_until(TIMEOUT, || {
+            for notification in rx.try_iter() {
+                match notification {
+                    UiNotification::Copied(_, to) => copied.push(to),
+                    UiNotification::CopyFailed(_, to, _) => failed.push(to),
+                    _ => {}
+                }
+            }
+            // A write is noticed when it starts and reported once it is done, both are copies.
+            copied.sort();
+            copied.dedup();
+            failed.dedup();
+            copied.len() == 2
+                && !failed.is_empty()
+                && has_contents(&base.join("share").join("app.exe"), b"New")
+                && has_contents(&base.join("rig1").join("app.exe"), b"New")
+        });
+        file_watcher.stop();
+
+        assert_eq!(
+            fs::read(base.join("share").join("app.exe")).unwrap(),
+            b"New"
+        );
+        assert_eq!(fs::read(base.join("rig1").join("app.exe")).unwrap(), b"New");
+        assert_eq!(
+            vec![
+                base.join("rig1").join("app.exe"),
+                base.join("share").join("app.exe")
+            ],
+            copied
+        );
+        assert_eq!(vec![base.join("rig2").join("app.exe")], failed);
+    }
+
+    #[test]
+    fn test_removes_and_renames_follow_policy() {
+        let base = TempDir::new("file_watcher_policy");
+        fs::create_dir_all(base.join("bin")).unwrap();
+        fs::write(base.join("bin").join("a.dll"), b"A").unwrap();
+        fs::write(base.join("bin").join("b.dll"), b"B").unwrap();
+
+        let glob = GlobDescription::new(
+            &base.join("bin").join("*.dll").to_string_lossy(),
+            &base
+                .join("share")
+                .join(GlobDescription::FILENAME)
+                .to_string_lossy(),
+        )
+        .unwrap();
+        let configuration = Configuration {
+            files: Vec::new(),
+            globs: vec![GlobDescription {
+                options: CopyOptions {
+                    on_remove: RemovePolicy::Quarantine(base.join("quarantine")),
+                    on_rename: RenamePolicy::Rename,
+                    ..CopyOptions::default()
+                },
+                ..glob
+            }],
+            directories: Vec::new(),
+        };
+        let mut file_watcher = FileWatcher::new(configuration, false);
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+        fs::write(base.join("bin").join("a.dll"), b"A2").unwrap();
+        fs::write(base.join("bin").join("b.dll"), b"B2").unwrap();
+        wait_until(TIMEOUT, || {
+            has_contents(&base.join("share").join("a.dll"), b"A2")
+                && has_contents(&base.join("share").join("b.dll"), b"B2")
+        });
+
+        fs::rename(
+            base.join("bin").join("a.dll"),
+            base.join("bin").join("c.dll"),
+        )
+        .unwrap();
+        fs::remove_file(base.join("bin").join("b.dll")).unwrap();
+        let mut notifications: Vec<UiNotification> = Vec::new();
+        wait_until(TIMEOUT, || {
+            notifications.extend(rx.try_iter());
+            let renamed = notifications
+                .iter()
+                .any(|n| matches!(n, UiNotification::Renamed(_, new) if new.ends_with("c.dll")));
+            let quarantined = notifications.iter().any(
+                |n| matches!(n, UiNotification::Quarantined(from, _) if from.ends_with("b.dll")),
+            );
+            renamed && quarantined
+        });
+        file_watcher.stop();
+
+        assert!(!base.join("share").join("a.dll").exists());
+        assert_eq!(fs::read(base.join("share").join("c.dll")).unwrap(), b"A2");
+        assert!(!base.join("share").join("b.dll").exists());
+        let quarantined: Vec<PathBuf> = fs::read_dir(base.join("quarantine"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(1, quarantined.len());
+        assert_eq!(fs::read(&quarantined[0]).unwrap(), b"B2");
+    }
+
+    /// A file whose directory doesn't exist yet is copied once it is created, with `use_polling`
+    /// or without it.
+    fn waits_for_missing_file(use_polling: bool) {
+        let base = TempDir::new("file_watcher_missing");
+        let from = base.join("bin").join("Debug").join("app.exe");
+        let to = base.join("share").join("app.exe");
+
+        let configuration = Configuration {
+            files: vec![FileDescription {
+                from: from.clone(),
+                to: to.clone(),
+                options: CopyOptions::default(),
+            }],
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        let mut file_watcher = FileWatcher::new(configuration, use_polling);
+        let rx = file_watcher.get_ui_notification_receiver();
+        file_watcher = start(file_watcher);
+        assert!(rx
+            .try_iter()
+            .any(|n| matches!(n, UiNotification::Waiting(p) if p == from)));
+
+        // Neither the file nor its directory exist yet. Watching the new directory instead isn't
+        // reported.
+        fs::create_dir_all(from.parent().unwrap()).unwrap();
+        fs::write(&from, b"First").unwrap();
+        wait_until(TIMEOUT, || has_contents(&to, b"First"));
+
+        // Deleted and created again, like some editors and linkers do.
+        fs::remove_file(&from).unwrap();
+        wait_until(TIMEOUT, || {
+            rx.try_iter()
+                .any(|n| matches!(n, UiNotification::Waiting(p) if p == from))
+        });
+        fs::write(&from, b"Second").unwrap();
+        wait_until(TIMEOUT, || has_contents(&to, b"Second"));
+        file_watcher.stop();
+    }
+
+    #[test]
+    fn test_waits_for_missing_file() {
+        waits_for_missing_file(false);
+    }
+
+    #[test]
+    fn test_polling_waits_for_missing_file() {
+        waits_for_missing_file(true);
+    }
+
+    #[test]
+    fn test_sources_are_canonicalized() {
+        let base = TempDir::new("file_watcher_canonical");
+        fs::create_dir_all(base.join("bin")).unwrap();
+        // The same directory, relative to the current one.
+        let mut relative: PathBuf = env::current_dir()
+            .unwrap()
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .map(|_| Component::ParentDir)
+            .collect();
+        relative.extend(
+            base.components()
+                .filter(|c| matches!(c, Component::Normal(_))),
+        );
+        let mut sources = vec![
+            relative.join(".").join("relative.exe"),
+            base.join("bin").join("..").join("bin").join("dotdot.exe"),
+        ];
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("bin"), base.join("link")).unwrap();
+            sources.push(base.join("link").join("symlinked.exe"));
+        }
+        for source in &sources {
+            fs::write(source, b"").unwrap();
+        }
+
+        let files = sources
+            .iter()
+            .map(|source| FileDescription {
+                from: source.clone(),
+                to: base.join("share").join(source.file_name().unwrap()),
+                options: CopyOptions::default(),
+            })
+            .collect();
+        let configuration = Configuration {
+            files,
+            globs: Vec::new(),
+            directories: Vec::new(),
+        };
+        let file_watcher = start(FileWatcher::new(configuration, false));
+        for source in &sources {
+            fs::write(source, b"New").unwrap();
+        }
+        wait_until(TIMEOUT, || {
+            sources.iter().all(|source| {
+                has_contents(
+                    &base.join("share").join(source.file_name().unwrap()),
+                    b"New",
+                )
+            })
+        });
+        file_watcher.stop();
+    }
+}
@@ -1,24 +1,26 @@
 use notify::DebouncedEvent::*;
-use notify::RecursiveMode::Recursive;
-use notify::{
-    watcher, DebouncedEvent, PollWatcher, ReadDirectoryChangesWatcher, RecursiveMode, Watcher,
-};
+use notify::{watcher, DebouncedEvent, PollWatcher, RecursiveMode, Watcher};
 use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
-use std::sync::RwLock;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::{Duration, SystemTime};
 use std::{fs, thread};
 
+/// The watcher that asks the OS to notify us of events on this platform.
+#[cfg(target_os = "linux")]
+type OsWatcher = notify::INotifyWatcher;
+#[cfg(not(target_os = "linux"))]
+type OsWatcher = notify::RecommendedWatcher;
+
 /// Represents all possible file watchers.
+#[allow(dead_code)]
 pub enum MyWatcher {
     Pw(PollWatcher),
     MyPw(PollingWatcher),
-    Rdcw(ReadDirectoryChangesWatcher),
+    Os(OsWatcher),
 }
 
 impl MyWatcher {
@@ -36,21 +38,10 @@ impl MyWatcher {
         tx: Sender<DebouncedEvent>,
         use_polling: bool,
     ) -> Result<MyWatcher, notify::Error> {
-        match use_polling {
-            true => match PollingWatcher::new(tx, Duration::from_millis(100)) {
-                Ok(v) => Ok(MyWatcher::MyPw(v)),
-                Err(e) => {
-                    println!("{:?}", e);
-                    Err(e)?
-                }
-            },
-            false => match watcher(tx, Duration::from_millis(100)) {
-                Ok(v) => Ok(MyWatcher::Rdcw(v)),
-                Err(e) => {
-                    println!("{:?}", e);
-                    Err(e)
-                }
-            },
+        if use_polling {
+            PollingWatcher::new(tx, Duration::from_millis(100)).map(MyWatcher::MyPw)
+        } else {
+            watcher(tx, Duration::from_millis(100)).map(MyWatcher::Os)
         }
     }
 
@@ -62,7 +53,7 @@ impl MyWatcher {
         match self {
             MyWatcher::Pw(w) => w.watch(path, recursive_mode),
             MyWatcher::MyPw(w) => w.watch(path, recursive_mode),
-            MyWatcher::Rdcw(w) => w.watch(path, recursive_mode),
+            MyWatcher::Os(w) => w.watch(path, recursive_mode),
         }
     }
 
@@ -70,63 +61,170 @@ impl MyWatcher {
         match self {
             MyWatcher::Pw(w) => w.unwatch(path),
             MyWatcher::MyPw(w) => w.unwatch(path),
-            MyWatcher::Rdcw(w) => w.unwatch(path),
+            MyWatcher::Os(w) => w.unwatch(path),
         }
     }
 }
 
-struct PollingWatcher {
-    files_to_watch: Arc<Mutex<HashSet<PathBuf>>>,
-    watcher_thread: JoinHandle<()>,
+pub struct PollingWatcher {
+    state: Arc<Mutex<PollState>>,
+    tx: Sender<DebouncedEvent>,
+    _watcher_thread: JoinHandle<()>,
+}
+
+/// What the polling thread shares with `watch` and `unwatch`.
+#[derive(Default)]
+struct PollState {
+    watched: HashMap<PathBuf, RecursiveMode>,
+    /// The last modification time of every path that was seen.
+    modified: HashMap<PathBuf, SystemTime>,
+    /// Directories that have been looked through at least once, so every file that shows up in
+    /// them afterwards is new.
+    scanned: HashSet<PathBuf>,
 }
 
 impl PollingWatcher {
     fn new(tx: Sender<DebouncedEvent>, dur: Duration) -> Result<PollingWatcher, notify::Error> {
-        let files_to_watch_vec: HashSet<PathBuf> = HashSet::new();
-        let files_to_watch = Arc::new(Mutex::new(files_to_watch_vec));
-        let files_to_watch_clone = files_to_watch.clone();
+        let state = Arc::new(Mutex::new(PollState::default()));
+        let state_clone = state.clone();
 
         let tx_clone = tx.clone();
-        let jh = thread::spawn(move || {
-            let mut hm: HashMap<PathBuf, SystemTime> = HashMap::new();
-            loop {
-                for p in &*files_to_watch_clone.lock().unwrap() {
-                    let metadata = match fs::metadata(&p) {
-                        Ok(v) => v,
-                        Err(_) => continue,
-                    };
-                    let modified_since = metadata.modified().unwrap();
-                    if hm.contains_key(p) {
-                        if modified_since > *hm.get(p).unwrap() {
-                            tx_clone.send(NoticeWrite(p.clone()));
-                            hm.insert(p.clone(), modified_since);
-                        }
-                    } else {
-                        hm.insert(p.clone(), modified_since);
-                    }
+        let jh = thread::spawn(move || loop {
+            {
+                let state = &mut *state_clone.lock().unwrap();
+                for (p, recursive_mode) in &state.watched {
+                    PollingWatcher::scan(
+                        &tx_clone,
+                        &mut state.modified,
+                        &mut state.scanned,
+                        p,
+                        *recursive_mode,
+                    );
                 }
-                thread::sleep_ms(100);
             }
+            thread::sleep(dur);
         });
         Ok(PollingWatcher {
-            files_to_watch: files_to_watch,
-            watcher_thread: jh,
+            state,
+            tx,
+            _watcher_thread: jh,
         })
     }
 
+    /// Reports what changed at `p` since it was last looked at.
+    fn scan(
+        tx: &Sender<DebouncedEvent>,
+        hm: &mut HashMap<PathBuf, SystemTime>,
+        scanned: &mut HashSet<PathBuf>,
+        p: &Path,
+        recursive_mode: RecursiveMode,
+    ) {
+        if !p.is_dir() {
+            PollingWatcher::poll(tx, hm, p, false);
+            return;
+        }
+        let report_new = scanned.contains(p);
+        let entries = PollingWatcher::entries_in(p, recursive_mode);
+        for entry in &entries {
+            PollingWatcher::poll(tx, hm, entry, report_new);
+        }
+        let found: HashSet<&PathBuf> = entries.iter().collect();
+        // Only what this scan could have found is gone, a nested directory that is watched as
+        // well reports its own entries.
+        let removed: Vec<PathBuf> = hm
+            .keys()
+            .filter(|k| PollingWatcher::could_contain(p, recursive_mode, k) && !found.contains(k))
+            .cloned()
+            .collect();
+        for file in removed {
+            hm.remove(&file);
+            let _ = tx.send(Remove(file));
+        }
+        scanned.insert(p.to_path_buf());
+    }
+
+    /// Compares the modification time of `p` with the last one we saw. Directories are only
+    /// reported when they are created, a file changing in them is reported for the file.
+    fn poll(
+        tx: &Sender<DebouncedEvent>,
+        hm: &mut HashMap<PathBuf, SystemTime>,
+        p: &Path,
+        report_new: bool,
+    ) {
+        let metadata = match fs::metadata(p) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let modified_since = metadata.modified().unwrap();
+        match hm.get(p) {
+            Some(last_modified) => {
+                if modified_since > *last_modified {
+                    if !metadata.is_dir() {
+                        let _ = tx.send(NoticeWrite(p.to_path_buf()));
+                    }
+                    hm.insert(p.to_path_buf(), modified_since);
+                }
+            }
+            None => {
+                if report_new {
+                    let _ = tx.send(Create(p.to_path_buf()));
+                }
+                hm.insert(p.to_path_buf(), modified_since);
+            }
+        }
+    }
+
+    /// Whether scanning `directory` can find `path`.
+    fn could_contain(directory: &Path, recursive_mode: RecursiveMode, path: &Path) -> bool {
+        match recursive_mode {
+            RecursiveMode::Recursive => path != directory && path.starts_with(directory),
+            RecursiveMode::NonRecursive => path.parent() == Some(directory),
+        }
+    }
+
+    /// The files and directories in `directory`, like the OS reports events for.
+    fn entries_in(directory: &Path, recursive_mode: RecursiveMode) -> Vec<PathBuf> {
+        let mut entries: Vec<PathBuf> = Vec::new();
+        let mut directories: Vec<PathBuf> = vec![directory.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let read = match fs::read_dir(&directory) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for entry in read.flatten() {
+                let path = entry.path();
+                if path.is_dir() && recursive_mode == RecursiveMode::Recursive {
+                    directories.push(path.clone());
+                }
+                entries.push(path);
+            }
+        }
+        entries
+    }
+
     fn watch<P: AsRef<Path>>(
         &mut self,
         path: P,
         recursive_mode: RecursiveMode,
     ) -> Result<(), notify::Error> {
-        let mut v = &mut *self.files_to_watch.lock().unwrap();
-        v.insert(MyWatcher::transform_path(path));
+        let path = MyWatcher::transform_path(path);
+        let state = &mut *self.state.lock().unwrap();
+        // What is there once watching starts isn't new, like with the OS watchers, even when
+        // the next poll is still some time away.
+        PollingWatcher::scan(
+            &self.tx,
+            &mut state.modified,
+            &mut state.scanned,
+            &path,
+            recursive_mode,
+        );
+        state.watched.insert(path, recursive_mode);
         Ok(())
     }
 
     fn unwatch<P: AsRef<Path>>(&mut self, path: P) -> Result<(), notify::Error> {
-        let mut v = &mut *self.files_to_watch.lock().unwrap();
-        v.remove(&path.as_ref().to_path_buf());
+        let state = &mut *self.state.lock().unwrap();
+        state.watched.remove(&MyWatcher::transform_path(path));
         Ok(())
     }
 }
@@ -134,23 +232,25 @@ impl PollingWatcher {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{wait_until, TempDir, TIMEOUT};
     use std::fs::File;
     use std::io::Write;
+    use std::sync::mpsc::{channel, Receiver, TryRecvError};
 
     fn test_watcher(mut pw: MyWatcher, rx: Receiver<DebouncedEvent>, filename: &str) {
         let mut f = File::create(filename).unwrap();
-        f.sync_all();
-        pw.watch(filename, RecursiveMode::NonRecursive);
+        f.sync_all().unwrap();
+        pw.watch(filename, RecursiveMode::NonRecursive).unwrap();
         thread::sleep(Duration::from_secs(1));
 
         assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
 
-        f.write_all(b"Test");
-        f.sync_all();
+        f.write_all(b"Test").unwrap();
+        f.sync_all().unwrap();
         drop(f);
 
         thread::sleep(Duration::from_secs(1));
-        fs::remove_file(filename);
+        fs::remove_file(filename).unwrap();
 
         assert_eq!(
             rx.try_recv(),
@@ -173,4 +273,53 @@ mod tests {
         let pw = MyWatcher::get_watcher(tx, false).unwrap();
         test_watcher(pw, rx, "os_watcher_text.txt");
     }
+
+    #[test]
+    fn test_polling_watcher_directory() {
+        let (tx, rx) = channel();
+        let mut pw = MyWatcher::get_watcher(tx, true).unwrap();
+        let directory = TempDir::new("polling_watcher_directory");
+        fs::create_dir_all(directory.join("nested")).unwrap();
+        // Files that are there when watching starts aren't new.
+        pw.watch(&directory, RecursiveMode::Recursive).unwrap();
+
+        let filename = directory.join("nested").join("new.txt");
+        File::create(&filename).unwrap();
+        let mut events: Vec<DebouncedEvent> = Vec::new();
+        wait_until(TIMEOUT, || {
+            events.extend(rx.try_iter());
+            !events.is_empty()
+        });
+        assert_eq!(vec![DebouncedEvent::Create(filename)], events);
+    }
+
+    #[test]
+    fn test_polling_watcher_nested_directories() {
+        let (tx, rx) = channel();
+        let mut pw = MyWatcher::get_watcher(tx, true).unwrap();
+        let directory = TempDir::new("polling_watcher_nested");
+        fs::create_dir_all(directory.join("nested")).unwrap();
+        fs::write(directory.join("app.exe"), b"").unwrap();
+        fs::write(directory.join("nested").join("lib.dll"), b"").unwrap();
+        // Like the rules for `app.exe` and `nested/lib.dll` do.
+        pw.watch(&directory, RecursiveMode::NonRecursive).unwrap();
+        pw.watch(directory.join("nested"), RecursiveMode::NonRecursive)
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(
+            Vec::<DebouncedEvent>::new(),
+            rx.try_iter().collect::<Vec<_>>()
+        );
+
+        let filename = directory.join("nested").join("new.txt");
+        File::create(&filename).unwrap();
+        let mut events: Vec<DebouncedEvent> = Vec::new();
+        wait_until(TIMEOUT, || {
+            events.extend(rx.try_iter());
+            !events.is_empty()
+        });
+        thread::sleep(Duration::from_millis(500));
+        events.extend(rx.try_iter());
+        assert_eq!(vec![DebouncedEvent::Create(filename)], events);
+    }
 }
@@ -1,27 +1,46 @@
-use notify::DebouncedEvent::*;
-use notify::RecursiveMode::Recursive;
-use notify::{
-    watcher, DebouncedEvent, PollWatcher, ReadDirectoryChangesWatcher, RecursiveMode, Watcher,
-};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
-use std::sync::RwLock;
-use std::thread::JoinHandle;
-use std::time::Duration;
-use std::{fs, thread};
+use notify::DebouncedEvent;
+use std::path::PathBuf;
+
+use crate::configuration_reader::Configuration;
 
 pub enum Notification {
     E(DebouncedEvent),
     B(bool),
+    /// The configuration file changed; either the new configuration or why it couldn't be parsed.
+    Reload(Result<Configuration, String>),
 }
 
 pub enum UiNotification {
     Started,
     StartedWatching(PathBuf),
     StoppedWatching(PathBuf),
+    /// The source doesn't exist, it is copied once it does.
+    Waiting(PathBuf),
+    /// The copy from the source to the destination waits for a worker.
+    Queued(PathBuf, PathBuf),
+    /// The source is being copied to the destination.
+    Copying(PathBuf, PathBuf),
     Copied(PathBuf, PathBuf),
+    /// The destination already had the same content as the source, so it wasn't copied.
+    Skipped(PathBuf, PathBuf),
+    /// The copy from the source to the destination gave up, with the last error.
+    CopyFailed(PathBuf, PathBuf, String),
+    /// The queued copy from the source to the destination was dropped, because the source is
+    /// gone or a handoff isn't complete yet.
+    Cancelled(PathBuf, PathBuf),
+    /// The destination of this source was deleted, because the source was removed.
+    Removed(PathBuf),
+    /// The destination of the source was moved to this path in the quarantine, because the
+    /// source was removed.
+    Quarantined(PathBuf, PathBuf),
+    /// The mirror of this source was renamed, because the source was.
+    Renamed(PathBuf, PathBuf),
+    ConfigurationReloaded,
+    /// The changed configuration file couldn't be parsed, so the previous one is still used.
+    ConfigurationError(String),
+    /// Something went wrong that doesn't belong to a copy, such as a path that couldn't be
+    /// watched.
+    Warning(String),
 }
 
 impl Clone for UiNotification {
@@ -30,7 +49,25 @@ impl Clone for UiNotification {
             UiNotification::Started => UiNotification::Started,
             UiNotification::StartedWatching(pb) => UiNotification::StartedWatching(pb.clone()),
             UiNotification::StoppedWatching(pb) => UiNotification::StoppedWatching(pb.clone()),
+            UiNotification::Waiting(pb) => UiNotification::Waiting(pb.clone()),
+            UiNotification::Queued(from, to) => UiNotification::Queued(from.clone(), to.clone()),
+            UiNotification::Copying(from, to) => UiNotification::Copying(from.clone(), to.clone()),
             UiNotification::Copied(from, to) => UiNotification::Copied(from.clone(), to.clone()),
+            UiNotification::Skipped(from, to) => UiNotification::Skipped(from.clone(), to.clone()),
+            UiNotification::CopyFailed(from, to, e) => {
+                UiNotification::CopyFailed(from.clone(), to.clone(), e.clone())
+            }
+            UiNotification::Cancelled(from, to) => {
+                UiNotification::Cancelled(from.clone(), to.clone())
+            }
+            UiNotification::Removed(pb) => UiNotification::Removed(pb.clone()),
+            UiNotification::Quarantined(from, to) => {
+                UiNotification::Quarantined(from.clone(), to.clone())
+            }
+            UiNotification::Renamed(old, new) => UiNotification::Renamed(old.clone(), new.clone()),
+            UiNotification::ConfigurationReloaded => UiNotification::ConfigurationReloaded,
+            UiNotification::ConfigurationError(e) => UiNotification::ConfigurationError(e.clone()),
+            UiNotification::Warning(message) => UiNotification::Warning(message.clone()),
         }
     }
 }
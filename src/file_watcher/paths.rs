@@ -0,0 +1,155 @@
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Makes `path` absolute and resolves `.`, `..` and symbolic links, the way the OS reports the
+/// path in events. The longest part of the path that exists is resolved by the OS, so `link/..`
+/// is the parent of the target of `link`, like the OS has it. The rest doesn't exist yet, so its
+/// `.` and `..` are resolved by name.
+pub fn canonicalize(path: &Path) -> PathBuf {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir().unwrap_or_default().join(path)
+    };
+
+    let components: Vec<Component> = absolute.components().collect();
+    let (mut result, rest) = (0..=components.len())
+        .rev()
+        .find_map(|existing| {
+            let prefix: PathBuf = components[..existing].iter().collect();
+            fs::canonicalize(&prefix)
+                .ok()
+                .map(|v| (strip_verbatim(v), &components[existing..]))
+        })
+        .unwrap_or((PathBuf::new(), &components[..]));
+    for component in rest {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Windows canonicalizes to `\\?\C:\...`, while events are reported as `C:\...`.
+#[cfg(windows)]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    let s = path.to_string_lossy();
+    match s.strip_prefix(r"\\?\UNC\") {
+        Some(rest) => PathBuf::from(format!(r"\\{}", rest)),
+        None => match s.strip_prefix(r"\\?\") {
+            Some(rest) => PathBuf::from(rest),
+            None => path.clone(),
+        },
+    }
+}
+
+#[cfg(not(windows))]
+fn strip_verbatim(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// The key to look up `path` by: its canonical path, compared case-insensitively on platforms
+/// whose filesystems are.
+pub fn key(path: &Path) -> PathBuf {
+    let canonical = canonicalize(path);
+    if cfg!(any(windows, target_os = "macos")) {
+        PathBuf::from(canonical.to_string_lossy().to_lowercase())
+    } else {
+        canonical
+    }
+}
+
+/// The device `path` is on, or will be on once it exists.
+#[cfg(unix)]
+pub fn device(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    canonicalize(path)
+        .ancestors()
+        .find_map(|p| fs::metadata(p).ok())
+        .map(|m| m.dev())
+        .unwrap_or_default()
+}
+
+/// There is no stable device id on other platforms, so the drive or share the path is on stands
+/// for it.
+#[cfg(not(unix))]
+pub fn device(path: &Path) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    key(path).components().next().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn test_canonicalize() {
+        let directory = TempDir::new("paths");
+        let base = canonicalize(&directory);
+        fs::create_dir_all(base.join("bin")).unwrap();
+        fs::write(base.join("bin").join("app.exe"), b"").unwrap();
+
+        // Relative to the current directory.
+        assert_eq!(
+            env::current_dir().unwrap().join("app.exe"),
+            canonicalize(Path::new("app.exe"))
+        );
+        assert_eq!(
+            canonicalize(&env::current_dir().unwrap().join("app.exe")),
+            canonicalize(Path::new("./app.exe"))
+        );
+
+        let expected = base.join("bin").join("app.exe");
+        assert_eq!(
+            expected,
+            canonicalize(&base.join("bin").join("..").join("bin").join("app.exe"))
+        );
+        assert_eq!(
+            expected,
+            canonicalize(&base.join("bin").join(".").join("app.exe"))
+        );
+        // The part that doesn't exist is kept.
+        assert_eq!(
+            base.join("missing").join("app.exe"),
+            canonicalize(&base.join("bin").join("..").join("missing").join("app.exe"))
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("bin"), base.join("link")).unwrap();
+            assert_eq!(expected, canonicalize(&base.join("link").join("app.exe")));
+            assert_eq!(
+                base.join("bin").join("new.exe"),
+                canonicalize(&base.join("link").join("new.exe"))
+            );
+            // `..` after a link is the parent of its target, like the OS has it.
+            fs::create_dir_all(base.join("bin").join("sub")).unwrap();
+            std::os::unix::fs::symlink(base.join("bin").join("sub"), base.join("sublink")).unwrap();
+            assert_eq!(
+                base.join("bin").join("missing.exe"),
+                canonicalize(&base.join("sublink").join("..").join("missing.exe"))
+            );
+            assert_eq!(
+                expected,
+                canonicalize(&base.join("sublink").join("..").join("app.exe"))
+            );
+        }
+
+        if cfg!(any(windows, target_os = "macos")) {
+            assert_eq!(key(&expected), key(&base.join("BIN").join("App.exe")));
+        } else {
+            assert_ne!(key(&expected), key(&base.join("BIN").join("App.exe")));
+        }
+
+        assert_eq!(device(&base), device(&base.join("missing").join("app.exe")));
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::thread::JoinHandle;
+
+use super::notifications::UiNotification;
+use super::paths;
+use crate::content;
+use crate::content::ContentCache;
+use crate::copy::{CopyOutcome, FileDescription, Group, Removal};
+
+/// How many copies run at the same time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CopyLimits {
+    /// The number of copies that run at the same time.
+    pub workers: usize,
+    /// The number of copies that write to the same device at the same time, so a slow share
+    /// doesn't take up every worker.
+    pub per_device: usize,
+}
+
+impl Default for CopyLimits {
+    fn default() -> CopyLimits {
+        CopyLimits {
+            workers: 4,
+            per_device: 2,
+        }
+    }
+}
+
+/// A copy, or another change to a destination, that waits for a worker.
+#[derive(Debug, Clone)]
+pub enum CopyJob {
+    Single(FileDescription),
+    /// The files of this group, which are copied together.
+    Group(Group, Vec<FileDescription>),
+    /// Handles the destination according to its rule, because the source was removed.
+    Remove(FileDescription),
+    /// Renames the destination of the first description to the one of the second, because the
+    /// source was renamed.
+    Rename(FileDescription, FileDescription),
+}
+
+/// The rule a job copies for. A rule has at most one job queued.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum JobKey {
+    Destination(PathBuf),
+    Group(Group),
+    /// A rename to this destination, which a copy to it doesn't replace.
+    Rename(PathBuf),
+}
+
+impl CopyJob {
+    fn key(&self) -> JobKey {
+        match self {
+            CopyJob::Single(fd) | CopyJob::Remove(fd) => JobKey::Destination(fd.to.clone()),
+            CopyJob::Group(group, _) => JobKey::Group(group.clone()),
+            CopyJob::Rename(_, new) => JobKey::Rename(new.to.clone()),
+        }
+    }
+
+    /// The files the job copies.
+    fn members(&self) -> &[FileDescription] {
+        match self {
+            CopyJob::Single(fd) => std::slice::from_ref(fd),
+            CopyJob::Group(_, members) => members,
+            CopyJob::Remove(_) | CopyJob::Rename(..) => &[],
+        }
+    }
+
+    /// The paths the job writes to.
+    fn destinations(&self) -> Vec<&PathBuf> {
+        match self {
+            CopyJob::Remove(fd) => vec![&fd.to],
+            CopyJob::Rename(old, new) => vec![&old.to, &new.to],
+            _ => self.members().iter().map(|fd| &fd.to).collect(),
+        }
+    }
+}
+
+/// The jobs that are queued and running, shared by the workers.
+#[derive(Default)]
+struct Queue {
+    /// The rules with a queued job, oldest first.
+    order: VecDeque<JobKey>,
+    /// The queued jobs with the devices they write to.
+    queued: HashMap<JobKey, (CopyJob, Vec<u64>)>,
+    /// The destinations that are being copied to. A destination is only written by one job at a
+    /// time, even when it is both copied on its own and as part of a group.
+    running: HashSet<PathBuf>,
+    /// The number of running copies by the device they write to.
+    devices: HashMap<u64, usize>,
+    stopping: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled whenever a job is queued or finished, or the scheduler stops.
+    changed: Condvar,
+    limits: CopyLimits,
+    transmitters: Vec<Sender<UiNotification>>,
+    /// Shared by the workers, so each of them knows what the others copied.
+    cache: ContentCache,
+    /// The generation of the last handoff that was copied onwards, by destination.
+    generations: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send_ui_notification(&self, notification: UiNotification) {
+        for tx in &self.transmitters {
+            let _ = tx.send(notification.clone());
+        }
+    }
+
+    /// Tells the UI how copying `from` to `to` ended.
+    fn report(&self, from: &Path, to: &Path, result: std::io::Result<CopyOutcome>) {
+        let notification = match result {
+            Ok(CopyOutcome::Copied(_)) => {
+                UiNotification::Copied(from.to_path_buf(), to.to_path_buf())
+            }
+            Ok(CopyOutcome::Skipped) => {
+                UiNotification::Skipped(from.to_path_buf(), to.to_path_buf())
+            }
+            Err(e) => {
+                UiNotification::CopyFailed(from.to_path_buf(), to.to_path_buf(), e.to_string())
+            }
+        };
+        self.send_ui_notification(notification);
+    }
+
+    /// Tells the UI what was done with the destination `to`, because its source `from` was
+    /// removed.
+    fn report_removal(&self, from: &Path, to: &Path, result: std::io::Result<Removal>) {
+        let notification = match result {
+            Ok(Removal::Kept) => return,
+            Ok(Removal::Deleted) => UiNotification::Removed(from.to_path_buf()),
+            Ok(Removal::Quarantined(path)) => UiNotification::Quarantined(from.to_path_buf(), path),
+            Err(e) => {
+                UiNotification::CopyFailed(from.to_path_buf(), to.to_path_buf(), e.to_string())
+            }
+        };
+        self.send_ui_notification(notification);
+    }
+
+    /// The generations of the markers of the sources in `members` that wait for a handoff, or
+    /// `None` when one of them doesn't match its marker. Such a source isn't complete yet, and
+    /// its marker will change again once it is.
+    fn handoff_generations(&self, members: &[FileDescription]) -> Option<Vec<(PathBuf, u64)>> {
+        members
+            .iter()
+            .filter(|fd| fd.options.check_handoff)
+            .map(|fd| {
+                let marker = content::read_marker(&fd.from).ok()?;
+                if !content::matches_marker(&fd.from, &marker).unwrap_or(false) {
+                    return None;
+                }
+                Some((fd.to.clone(), marker.generation))
+            })
+            .collect()
+    }
+
+    /// Whether every one of `generations` was already copied onwards.
+    fn handed_off(&self, generations: &[(PathBuf, u64)]) -> bool {
+        let recorded = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        !generations.is_empty()
+            && generations
+                .iter()
+                .all(|(to, generation)| recorded.get(to) == Some(generation))
+    }
+
+    fn record(&self, generations: Vec<(PathBuf, u64)>) {
+        let mut recorded = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        recorded.extend(generations);
+    }
+
+    /// Takes the oldest job that can run: none of its destinations are being copied to, and its
+    /// devices have room for another copy.
+    fn take_runnable(&self, queue: &mut Queue) -> Option<(CopyJob, Vec<u64>)> {
+        let index = queue.order.iter().position(|key| {
+            let (job, devices) = &queue.queued[key];
+            job.destinations()
+                .into_iter()
+                .all(|to| !queue.running.contains(to))
+                && devices.iter().all(|device| {
+                    queue.devices.get(device).copied().unwrap_or(0) < self.limits.per_device
+                })
+        })?;
+        let key = queue.order.remove(index)?;
+        let (job, devices) = queue.queued.remove(&key)?;
+        for device in &devices {
+            *queue.devices.entry(*device).or_default() += 1;
+        }
+        queue
+            .running
+            .extend(job.destinations().into_iter().cloned());
+        Some((job, devices))
+    }
+
+    /// Waits for a job that can run. Returns `None` once the scheduler stops.
+    fn next(&self) -> Option<(CopyJob, Vec<u64>)> {
+        let mut queue = self.lock();
+        loop {
+            if queue.stopping {
+                return None;
+            }
+            if let Some(v) = self.take_runnable(&mut queue) {
+                return Some(v);
+            }
+            queue = self.changed.wait(queue).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    fn finish(&self, job: &CopyJob, devices: &[u64]) {
+        let mut queue = self.lock();
+        for to in job.destinations() {
+            queue.running.remove(to);
+        }
+        for device in devices {
+            if let Some(count) = queue.devices.get_mut(device) {
+                *count -= 1;
+            }
+        }
+        drop(queue);
+        self.changed.notify_all();
+    }
+
+    fn work(&self) {
+        while let Some((job, devices)) = self.next() {
+            let running = Running {
+                shared: self,
+                job,
+                devices,
+            };
+            self.run(&running.job);
+        }
+    }
+
+    fn run(&self, job: &CopyJob) {
+        match job {
+            CopyJob::Remove(fd) => {
+                let result = fd.remove_destination();
+                self.report_removal(&fd.from, &fd.to, result);
+                return;
+            }
+            CopyJob::Rename(old, new) => {
+                let notification = match new.take_over(old) {
+                    Ok(_) => UiNotification::Renamed(old.from.clone(), new.from.clone()),
+                    Err(e) => {
+                        UiNotification::CopyFailed(new.from.clone(), new.to.clone(), e.to_string())
+                    }
+                };
+                self.send_ui_notification(notification);
+                return;
+            }
+            _ => {}
+        }
+        let cancel = || {
+            for fd in job.members() {
+                self.send_ui_notification(UiNotification::Cancelled(
+                    fd.from.clone(),
+                    fd.to.clone(),
+                ));
+            }
+        };
+        // A source that is gone by now is handled by its removal.
+        if matches!(job, CopyJob::Single(fd) if !fd.from.exists()) {
+            cancel();
+            return;
+        }
+        let generations = match self.handoff_generations(job.members()) {
+            Some(v) => v,
+            None => {
+                cancel();
+                return;
+            }
+        };
+        if let CopyJob::Single(fd) = job {
+            if self.handed_off(&generations) {
+                self.send_ui_notification(UiNotification::Skipped(fd.from.clone(), fd.to.clone()));
+                return;
+            }
+        }
+
+        for fd in job.members() {
+            self.send_ui_notification(UiNotification::Copying(fd.from.clone(), fd.to.clone()));
+        }
+        let results = match job {
+            CopyJob::Single(fd) => vec![fd.copy_with(&self.cache)],
+            CopyJob::Group(_, members) => FileDescription::copy_group(members, &self.cache),
+            CopyJob::Remove(_) | CopyJob::Rename(..) => Vec::new(),
+        };
+        // A generation that couldn't be copied is tried again when its marker is seen again.
+        if results.iter().all(Result::is_ok) {
+            self.record(generations);
+        }
+        for (fd, result) in job.members().iter().zip(results) {
+            self.report(&fd.from, &fd.to, result);
+        }
+    }
+}
+
+/// A job a worker took, which is finished when this is dropped, even when the copy panics.
+struct Running<'a> {
+    shared: &'a Shared,
+    job: CopyJob,
+    devices: Vec<u64>,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            for fd in self.job.members() {
+                self.shared.send_ui_notification(UiNotification::CopyFailed(
+                    fd.from.clone(),
+                    fd.to.clone(),
+                    String::from("The copy panicked"),
+                ));
+            }
+        }
+        self.shared.finish(&self.job, &self.devices);
+    }
+}
+
+/// Runs the copies, removals and renames on a pool of workers, so the watcher thread never waits
+/// for them. A change to a rule that is already queued replaces the queued job, and a rule that
+/// is being copied is copied again once that is done, so only the latest change is copied.
+pub struct Scheduler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(limits: CopyLimits, transmitters: Vec<Sender<UiNotification>>) -> Scheduler {
+        let limits = CopyLimits {
+            workers: limits.workers.max(1),
+            per_device: limits.per_device.max(1),
+        };
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            limits,
+            transmitters,
+            cache: ContentCache::default(),
+            generations: Mutex::new(HashMap::new()),
+        });
+        let workers = (0..limits.workers)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+        Scheduler { shared, workers }
+    }
+
+    /// Queues `job`, replacing the job of the same rule that is still queued.
+    pub fn submit(&self, job: CopyJob) {
+        let key = job.key();
+        let mut devices: Vec<u64> = job
+            .destinations()
+            .into_iter()
+            .map(|to| paths::device(to))
+            .collect();
+        devices.sort_unstable();
+        devices.dedup();
+
+        let mut queue = self.shared.lock();
+        let replaced = queue.queued.remove(&key).map(|(job, _)| job);
+        if replaced.is_none() {
+            queue.order.push_back(key.clone());
+        }
+        let replaced = replaced.as_ref().map(CopyJob::members).unwrap_or_default();
+        // Only the destinations that are new to the queue or left it are reported.
+        for fd in replaced {
+            if !job.members().iter().any(|m| m.to == fd.to) {
+                self.shared.send_ui_notification(UiNotification::Cancelled(
+                    fd.from.clone(),
+                    fd.to.clone(),
+                ));
+            }
+        }
+        for fd in job.members() {
+            if !replaced.iter().any(|r| r.to == fd.to) {
+                self.shared
+                    .send_ui_notification(UiNotification::Queued(fd.from.clone(), fd.to.clone()));
+            }
+        }
+        queue.queued.insert(key, (job, devices));
+        drop(queue);
+        self.shared.changed.notify_all();
+    }
+
+    /// Drops the queued copy to `to`, because its source is gone.
+    pub fn cancel(&self, to: &Path) {
+        let key = JobKey::Destination(to.to_path_buf());
+        let mut queue = self.shared.lock();
+        if let Some((job, _)) = queue.queued.remove(&key) {
+            queue.order.retain(|k| *k != key);
+            for fd in job.members() {
+                self.shared.send_ui_notification(UiNotification::Cancelled(
+                    fd.from.clone(),
+                    fd.to.clone(),
+                ));
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    /// Drops the queued copies, and waits for the ones that are running.
+    fn drop(&mut self) {
+        let mut queue = self.shared.lock();
+        queue.stopping = true;
+        queue.order.clear();
+        queue.queued.clear();
+        drop(queue);
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::CopyOptions;
+    use crate::test_support::TempDir;
+    use std::fs;
+    use std::sync::mpsc::channel;
+
+    /// Takes the next job that can run.
+    fn take(scheduler: &Scheduler) -> Option<(CopyJob, Vec<u64>)> {
+        let mut queue = scheduler.shared.lock();
+        scheduler.shared.take_runnable(&mut queue)
+    }
+
+    /// The source and destination of every member of `job`.
+    fn members(job: &CopyJob) -> Vec<(PathBuf, PathBuf)> {
+        job.members()
+            .iter()
+            .map(|fd| (fd.from.clone(), fd.to.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let base = TempDir::new("scheduler");
+        let fd = |from: &str, to: &str| FileDescription {
+            from: base.join(from),
+            to: base.join(to),
+            options: CopyOptions::default(),
+        };
+
+        // Without workers, the jobs are taken by hand.
+        let (tx, rx) = channel();
+        let scheduler = Scheduler {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue::default()),
+                changed: Condvar::new(),
+                limits: CopyLimits {
+                    workers: 1,
+                    per_device: 2,
+                },
+                transmitters: vec![tx],
+                cache: ContentCache::default(),
+                generations: Mutex::new(HashMap::new()),
+            }),
+            workers: Vec::new(),
+        };
+        scheduler.submit(CopyJob::Single(fd("a", "a.copy")));
+        scheduler.submit(CopyJob::Single(fd("b", "b.copy")));
+        scheduler.submit(CopyJob::Single(fd("new_b", "b.copy")));
+        scheduler.submit(CopyJob::Group(
+            Group::Named(String::from("group")),
+            vec![fd("c", "c.copy"), fd("d", "d.copy")],
+        ));
+        scheduler.submit(CopyJob::Single(fd("e", "e.copy")));
+        scheduler.cancel(&base.join("e.copy"));
+        let notifications: Vec<UiNotification> = rx.try_iter().collect();
+        let queued = notifications
+            .iter()
+            .filter(|n| matches!(n, UiNotification::Queued(..)))
+            .count();
+        assert_eq!(5, queued, "b is only queued once");
+        assert!(notifications
+            .iter()
+            .any(|n| matches!(n, UiNotification::Cancelled(from, _) if *from == base.join("e"))));
+
+        // The latest change to b replaced the queued one.
+        let (a, a_devices) = take(&scheduler).unwrap();
+        let (b, b_devices) = take(&scheduler).unwrap();
+        assert_eq!(vec![(base.join("new_b"), base.join("b.copy"))], members(&b));
+        // Everything is on the same device, which allows two copies at a time.
+        assert!(take(&scheduler).is_none());
+
+        // b changes again while it is being copied, so it is copied again afterwards.
+        scheduler.submit(CopyJob::Single(fd("b", "b.copy")));
+        // A group that copies to b.copy as well waits for it too.
+        scheduler.submit(CopyJob::Group(
+            Group::Named(String::from("other group")),
+            vec![fd("f", "f.copy"), fd("b", "b.copy")],
+        ));
+        scheduler.shared.finish(&a, &a_devices);
+        let (group, group_devices) = take(&scheduler).unwrap();
+        assert_eq!(2, members(&group).len());
+        assert_eq!(base.join("c.copy"), members(&group)[0].1);
+        assert!(take(&scheduler).is_none(), "b is still running");
+        scheduler.shared.finish(&group, &group_devices);
+        assert!(take(&scheduler).is_none(), "b is still running");
+        scheduler.shared.finish(&b, &b_devices);
+        let (job, job_devices) = take(&scheduler).unwrap();
+        assert_eq!(vec![(base.join("b"), base.join("b.copy"))], members(&job));
+        assert!(
+            take(&scheduler).is_none(),
+            "the other group copies to b.copy"
+        );
+        scheduler.shared.finish(&job, &job_devices);
+        let (job, _) = take(&scheduler).unwrap();
+        assert_eq!(base.join("f.copy"), members(&job)[0].1);
+        // The cancelled copy never runs.
+        assert!(take(&scheduler).is_none());
+    }
+
+    #[test]
+    fn test_finishes_when_a_copy_panics() {
+        let base = TempDir::new("scheduler_panic");
+        let (tx, rx) = channel();
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            changed: Condvar::new(),
+            limits: CopyLimits::default(),
+            transmitters: vec![tx],
+            cache: ContentCache::default(),
+            generations: Mutex::new(HashMap::new()),
+        });
+        let fd = FileDescription {
+            from: base.join("a"),
+            to: base.join("a.copy"),
+            options: CopyOptions::default(),
+        };
+        shared
+            .lock()
+            .order
+            .push_back(JobKey::Destination(fd.to.clone()));
+        shared.lock().queued.insert(
+            JobKey::Destination(fd.to.clone()),
+            (CopyJob::Single(fd), vec![1]),
+        );
+
+        let worker = shared.clone();
+        let result = thread::spawn(move || {
+            let (job, devices) = worker.next().unwrap();
+            let _running = Running {
+                shared: &worker,
+                job,
+                devices,
+            };
+            panic!("copy failed");
+        })
+        .join();
+        assert!(result.is_err());
+        let queue = shared.lock();
+        assert!(queue.running.is_empty());
+        assert_eq!(Some(&0), queue.devices.get(&1));
+        assert!(rx
+            .try_iter()
+            .any(|n| matches!(n, UiNotification::CopyFailed(..))));
+    }
+
+    #[test]
+    fn test_handoff_generations() {
+        let base = TempDir::new("scheduler_handoff");
+        let (tx, _rx) = channel();
+        let scheduler = Scheduler::new(CopyLimits::default(), vec![tx]);
+        let fd = FileDescription {
+            from: base.join("app.exe"),
+            to: base.join("rig").join("app.exe"),
+            options: CopyOptions {
+                check_handoff: true,
+                ..CopyOptions::default()
+            },
+        };
+        let members = std::slice::from_ref(&fd);
+
+        // Without a marker, or with one the file doesn't match, it isn't complete yet.
+        fs::write(&fd.from, b"First").unwrap();
+        assert_eq!(None, scheduler.shared.handoff_generations(members));
+        let sha256 = content::checksum(&fd.from, content::HashAlgorithm::Sha256).unwrap();
+        content::write_marker(&fd.from, sha256).unwrap();
+        fs::write(&fd.from, b"Second").unwrap();
+        assert_eq!(None, scheduler.shared.handoff_generations(members));
+
+        fs::write(&fd.from, b"First").unwrap();
+        let generations = scheduler.shared.handoff_generations(members).unwrap();
+        assert_eq!(vec![(fd.to.clone(), 1)], generations);
+        assert!(!scheduler.shared.handed_off(&generations));
+        scheduler.shared.record(generations.clone());
+        assert!(scheduler.shared.handed_off(&generations));
+    }
+}
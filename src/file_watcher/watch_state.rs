@@ -0,0 +1,686 @@
+use notify::DebouncedEvent;
+use notify::DebouncedEvent::*;
+use notify::RecursiveMode;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::my_watcher::MyWatcher;
+use super::notifications::UiNotification;
+use super::paths;
+use super::scheduler::{CopyJob, CopyLimits, Scheduler};
+use crate::configuration_reader::Configuration;
+use crate::content;
+use crate::copy::{FileDescription, Group, RenamePolicy, Settle};
+use crate::rules::{DirectoryDescription, GlobDescription};
+
+/// A copy that waits for its source to settle.
+struct Pending {
+    fd: FileDescription,
+    /// The size and modification time of the source when it was last looked at.
+    seen: Option<(u64, SystemTime)>,
+    /// Since when the source hasn't changed.
+    since: Instant,
+}
+
+/// How long none of the files of a named group may change before the group is copied. The files
+/// of such a group are usually written one after the other, so the first one settling doesn't
+/// mean the others are complete.
+const GROUP_SETTLE: Duration = Duration::from_millis(500);
+
+/// A group that waits for all of its files to settle.
+struct PendingGroup {
+    /// The size and modification time of every source when they were last looked at.
+    seen: Vec<Option<(u64, SystemTime)>>,
+    /// Since when none of the sources changed.
+    since: Instant,
+}
+
+/// Everything the watcher thread is currently watching, which can be replaced by a new
+/// configuration while running.
+pub struct WatchState {
+    watch: MyWatcher,
+    transmitters: Vec<Sender<UiNotification>>,
+    /// The files from the configuration, by the key of their source, see `paths::key`. A source
+    /// can be copied to several destinations.
+    files: HashMap<PathBuf, Vec<FileDescription>>,
+    /// The files that were found through a pattern, by the key of their source.
+    matched: HashMap<PathBuf, Vec<FileDescription>>,
+    globs: Vec<GlobDescription>,
+    directories: Vec<DirectoryDescription>,
+    /// Every path that was handed to the watcher.
+    watched: HashSet<PathBuf>,
+    /// The copies waiting for their source to settle, by destination. A change that arrives
+    /// while waiting doesn't add another copy.
+    pending: HashMap<PathBuf, Pending>,
+    /// The groups waiting for their sources to settle.
+    pending_groups: HashMap<Group, PendingGroup>,
+    scheduler: Scheduler,
+}
+
+impl WatchState {
+    pub fn new(
+        watch: MyWatcher,
+        transmitters: Vec<Sender<UiNotification>>,
+        limits: CopyLimits,
+    ) -> WatchState {
+        WatchState {
+            watch,
+            scheduler: Scheduler::new(limits, transmitters.clone()),
+            transmitters,
+            files: HashMap::new(),
+            matched: HashMap::new(),
+            globs: Vec::new(),
+            directories: Vec::new(),
+            watched: HashSet::new(),
+            pending: HashMap::new(),
+            pending_groups: HashMap::new(),
+        }
+    }
+
+    pub fn send_ui_notification(&self, notification: UiNotification) {
+        for tx in &self.transmitters {
+            let _ = tx.send(notification.clone());
+        }
+    }
+
+    /// Starts watching everything in `configuration` that isn't watched yet, and stops watching
+    /// everything that isn't in it anymore.
+    pub fn apply(&mut self, configuration: Configuration) {
+        // The sources are compared the way the OS reports them in events.
+        let mut files: HashMap<PathBuf, Vec<FileDescription>> = HashMap::new();
+        for mut fd in configuration.files {
+            fd.from = paths::canonicalize(&fd.from);
+            files.entry(paths::key(&fd.from)).or_default().push(fd);
+        }
+
+        // The files found through a pattern, unless the configuration names them itself.
+        let mut matched: HashMap<PathBuf, Vec<FileDescription>> = HashMap::new();
+        for glob in &configuration.globs {
+            for fd in glob.resolve() {
+                let key = paths::key(&fd.from);
+                if !files.contains_key(&key) {
+                    let copies = matched.entry(key).or_default();
+                    if !copies.iter().any(|c| c.to == fd.to) {
+                        copies.push(fd);
+                    }
+                }
+            }
+        }
+        let source = |fds: &Vec<FileDescription>| fds.first().map(|fd| fd.from.clone());
+        for (key, fds) in &self.matched {
+            if !matched.contains_key(key) {
+                if let Some(from) = source(fds) {
+                    self.send_ui_notification(UiNotification::StoppedWatching(from));
+                }
+            }
+        }
+        for (key, fds) in &matched {
+            if !self.matched.contains_key(key) {
+                if let Some(from) = source(fds) {
+                    self.send_ui_notification(UiNotification::StartedWatching(from));
+                }
+            }
+        }
+
+        // The files from the configuration are shown whether they exist or not, because they are
+        // watched through their directory.
+        for (key, fds) in &self.files {
+            if !files.contains_key(key) {
+                if let Some(from) = source(fds) {
+                    self.send_ui_notification(UiNotification::StoppedWatching(from));
+                }
+            }
+        }
+        for (key, fds) in &files {
+            if !self.files.contains_key(key) {
+                if let Some(from) = source(fds) {
+                    self.send_ui_notification(UiNotification::StartedWatching(from.clone()));
+                    if !from.exists() {
+                        self.send_ui_notification(UiNotification::Waiting(from));
+                    }
+                }
+            }
+        }
+        let sources = |directories: &[DirectoryDescription]| -> HashSet<PathBuf> {
+            directories.iter().map(|d| d.from.clone()).collect()
+        };
+        let (old_sources, new_sources) = (
+            sources(&self.directories),
+            sources(&configuration.directories),
+        );
+        for from in old_sources.difference(&new_sources) {
+            self.send_ui_notification(UiNotification::StoppedWatching(from.clone()));
+        }
+        for from in new_sources.difference(&old_sources) {
+            self.send_ui_notification(UiNotification::StartedWatching(from.clone()));
+        }
+
+        let mut failures = Vec::new();
+        for directory in &configuration.directories {
+            if !self.directories.contains(directory) {
+                for fd in directory.synchronize(&mut failures) {
+                    self.scheduler.submit(CopyJob::Single(fd));
+                }
+            }
+        }
+        for (to, e) in failures {
+            self.send_ui_notification(UiNotification::Warning(format!(
+                "Could not remove {:?}, because: {}",
+                to, e
+            )));
+        }
+
+        // A file that was handed off while nothing was watching it is copied onwards now.
+        let handoffs: Vec<FileDescription> = files
+            .values()
+            .chain(matched.values())
+            .flatten()
+            .filter(|fd| fd.options.check_handoff && content::marker_path(&fd.from).exists())
+            .filter(|fd| {
+                !self
+                    .files
+                    .values()
+                    .chain(self.matched.values())
+                    .flatten()
+                    .any(|old| old.from == fd.from && old.to == fd.to)
+            })
+            .cloned()
+            .collect();
+
+        self.files = files;
+        self.matched = matched;
+        self.globs = configuration.globs;
+        self.directories = configuration.directories;
+        self.synchronize_watches();
+        for fd in handoffs {
+            self.schedule(fd);
+        }
+    }
+
+    /// Hands every path we need to the watcher, and takes back every path we don't need anymore.
+    fn synchronize_watches(&mut self) {
+        let mut needed: HashMap<PathBuf, RecursiveMode> = HashMap::new();
+        // Files are watched through their directory, so they can be missing, and replacing them
+        // doesn't lose the watch. Until the directory exists, the closest one that does is
+        // watched, to notice it being created.
+        for fd in self.files.values().flatten() {
+            if let Some(directory) = fd.from.ancestors().skip(1).find(|a| a.is_dir()) {
+                needed
+                    .entry(directory.to_path_buf())
+                    .or_insert(RecursiveMode::NonRecursive);
+            }
+        }
+        // The files matching a pattern are watched through the directory they are in, so files
+        // that only appear later are picked up as well.
+        for glob in &self.globs {
+            needed.insert(glob.base.clone(), RecursiveMode::Recursive);
+        }
+        for directory in &self.directories {
+            needed.insert(directory.from.clone(), RecursiveMode::Recursive);
+        }
+
+        let unneeded: Vec<PathBuf> = self
+            .watched
+            .iter()
+            .filter(|p| !needed.contains_key(*p))
+            .cloned()
+            .collect();
+        for p in unneeded {
+            self.unwatch(p);
+        }
+
+        let mut to_watch: Vec<(PathBuf, RecursiveMode)> = needed
+            .into_iter()
+            .filter(|(p, _)| !self.watched.contains(p))
+            .collect();
+        to_watch.sort_by(|a, b| a.0.cmp(&b.0));
+        for (p, recursive_mode) in to_watch {
+            match self.watch.watch(&p, recursive_mode) {
+                Ok(_) => {
+                    self.watched.insert(p);
+                }
+                Err(e) => {
+                    self.send_ui_notification(UiNotification::Warning(format!(
+                        "Couldn't watch {:?}, because: {}",
+                        p, e
+                    )));
+                }
+            };
+        }
+    }
+
+    fn unwatch(&mut self, p: PathBuf) {
+        self.watched.remove(&p);
+        if let Err(e) = self.watch.unwatch(&p) {
+            self.send_ui_notification(UiNotification::Warning(format!(
+                "Could not stop watching {:?}, because: {}",
+                p, e
+            )));
+        }
+    }
+
+    /// Stops watching everything.
+    pub fn unwatch_all(&mut self) {
+        let mut watched: Vec<PathBuf> = self.watched.iter().cloned().collect();
+        watched.sort();
+        for p in watched {
+            self.unwatch(p);
+        }
+    }
+
+    /// Watches again after the watched directory `p` was removed or created, so the files in it
+    /// are watched through the closest directory that exists now.
+    fn rearm(&mut self, p: &Path) {
+        if self.watched.remove(p) {
+            let _ = self.watch.unwatch(p);
+        }
+        self.synchronize_watches();
+        // Files can show up in a new directory before it is watched.
+        let p = paths::canonicalize(p);
+        let copies: Vec<FileDescription> = self
+            .files
+            .values()
+            .flatten()
+            .filter(|fd| fd.from.starts_with(&p) && fd.from.is_file())
+            .cloned()
+            .collect();
+        for fd in copies {
+            self.copy(fd);
+        }
+    }
+
+    /// Whether `p` is a directory that the directory of a file from the configuration is in, or
+    /// will be in once it is created.
+    fn leads_to_file(&self, p: &Path) -> bool {
+        let p = paths::canonicalize(p);
+        self.files
+            .values()
+            .flatten()
+            .any(|fd| fd.from.parent().map(|d| d.starts_with(&p)).unwrap_or(false))
+    }
+
+    /// Handles the destinations of the removed source `p` according to their rules.
+    fn remove(&mut self, p: PathBuf) {
+        let key = paths::key(&p);
+        let mut fds = self.files.get(&key).cloned().unwrap_or_default();
+        if let Some(matched) = self.matched.remove(&key) {
+            self.send_ui_notification(UiNotification::StoppedWatching(p.clone()));
+            fds.extend(matched);
+        }
+        fds.extend(
+            self.directories
+                .iter()
+                .filter_map(|d| d.file_description(&p)),
+        );
+        for fd in fds {
+            // Replaces a copy to the destination that is still queued.
+            self.scheduler.submit(CopyJob::Remove(fd));
+        }
+    }
+
+    /// The destinations to rename because their source was renamed from `old` to `new`, as the
+    /// old and the new description. Only patterns and directories can rename their destinations,
+    /// the destination of a file from the configuration doesn't depend on its source.
+    fn renames(&self, old: &Path, new: &Path) -> Vec<(FileDescription, FileDescription)> {
+        let globs = self
+            .globs
+            .iter()
+            .filter_map(|g| Some((g.file_description(old)?, g.file_description(new)?)));
+        let directories = self
+            .directories
+            .iter()
+            .filter_map(|d| Some((d.file_description(old)?, d.file_description(new)?)));
+        globs
+            .chain(directories)
+            .filter(|(old, _)| old.options.on_rename == RenamePolicy::Rename && old.to.exists())
+            .collect()
+    }
+
+    /// Copies `fd` because its source changed, unless it waits for a handoff instead.
+    fn copy(&mut self, fd: FileDescription) {
+        if !fd.options.check_handoff {
+            self.schedule(fd);
+        }
+    }
+
+    /// The rules that copy `path`, because the configuration names it or it was found through a
+    /// pattern.
+    fn rules(&self, path: &Path) -> Vec<FileDescription> {
+        let key = paths::key(path);
+        self.files
+            .get(&key)
+            .or_else(|| self.matched.get(&key))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the rules to copy `path` with that wait for a handoff.
+    fn receiving_rules(&self, path: &Path) -> Vec<FileDescription> {
+        let mut rules = self.rules(path);
+        if rules.is_empty() {
+            rules = self
+                .globs
+                .iter()
+                .filter_map(|g| g.file_description(path))
+                .chain(
+                    self.directories
+                        .iter()
+                        .filter_map(|d| d.file_description(path)),
+                )
+                .collect();
+        }
+        rules.retain(|fd| fd.options.check_handoff);
+        rules
+    }
+
+    /// Copies `fd` onwards because its marker changed. The worker only copies it when the
+    /// marker has a new generation that the source matches.
+    fn handoff(&mut self, fd: FileDescription) {
+        if !fd.from.is_file() {
+            return;
+        }
+        let key = paths::key(&fd.from);
+        let found_by_pattern = !self.files.contains_key(&key)
+            && !self.matched.contains_key(&key)
+            && self.globs.iter().any(|g| g.matches(&fd.from));
+        if found_by_pattern {
+            self.send_ui_notification(UiNotification::StartedWatching(fd.from.clone()));
+        }
+        if found_by_pattern || self.matched.contains_key(&key) {
+            let copies = self.matched.entry(key).or_default();
+            if !copies.iter().any(|c| c.to == fd.to) {
+                copies.push(fd.clone());
+            }
+        }
+        self.schedule(fd);
+    }
+
+    /// Queues the copy of `fd` once its source has settled, or of its whole group once all of
+    /// their sources have.
+    fn schedule(&mut self, fd: FileDescription) {
+        if let Some(group) = fd.options.group {
+            self.pending_groups.entry(group).or_insert(PendingGroup {
+                seen: Vec::new(),
+                since: Instant::now(),
+            });
+            return;
+        }
+        if fd.options.settle == Settle::Immediately {
+            self.scheduler.submit(CopyJob::Single(fd));
+            return;
+        }
+        let to = fd.to.clone();
+        self.pending.entry(to).or_insert(Pending {
+            fd,
+            seen: None,
+            since: Instant::now(),
+        });
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty() || !self.pending_groups.is_empty()
+    }
+
+    /// The files of `group`, in the order of their destinations.
+    fn group_members(&self, group: &Group) -> Vec<FileDescription> {
+        let mut members: Vec<FileDescription> = self
+            .files
+            .values()
+            .chain(self.matched.values())
+            .flatten()
+            .filter(|fd| fd.options.group.as_ref() == Some(group))
+            .cloned()
+            .collect();
+        members.sort_by(|a, b| a.to.cmp(&b.to));
+        members
+    }
+
+    /// Queues the copy of every pending source that has settled by now.
+    pub fn poll_pending(&mut self) {
+        let mut settled: Vec<PathBuf> = Vec::new();
+        for (to, pending) in &mut self.pending {
+            let metadata = match std::fs::metadata(&pending.fd.from) {
+                Ok(v) => v,
+                Err(_) => {
+                    // Gone before it settled, there is nothing to copy anymore.
+                    settled.push(to.clone());
+                    continue;
+                }
+            };
+            let seen = metadata.modified().ok().map(|m| (metadata.len(), m));
+            if seen.is_none() || seen != pending.seen {
+                pending.seen = seen;
+                pending.since = Instant::now();
+                continue;
+            }
+            let ready = match pending.fd.options.settle {
+                Settle::Immediately => true,
+                Settle::Stable(duration) => pending.since.elapsed() >= duration,
+                Settle::ExclusiveOpen => pending.fd.can_open_exclusively(),
+            };
+            if ready {
+                settled.push(to.clone());
+            }
+        }
+        settled.sort();
+        for to in settled {
+            if let Some(pending) = self.pending.remove(&to) {
+                if pending.fd.from.exists() {
+                    self.scheduler.submit(CopyJob::Single(pending.fd));
+                }
+            }
+        }
+        self.poll_pending_groups();
+    }
+
+    /// Queues the copy of every pending group whose sources have all settled by now.
+    fn poll_pending_groups(&mut self) {
+        let mut groups: Vec<Group> = self.pending_groups.keys().cloned().collect();
+        groups.sort();
+        for group in groups {
+            let members = self.group_members(&group);
+            let seen: Option<Vec<Option<(u64, SystemTime)>>> = members
+                .iter()
+                .map(|fd| {
+                    let metadata = std::fs::metadata(&fd.from).ok()?;
+                    Some(metadata.modified().ok().map(|m| (metadata.len(), m)))
+                })
+                .collect();
+            let seen = match seen {
+                Some(v) if !members.is_empty() => v,
+                // Part of the group is gone, so it can't be copied as a whole. It is scheduled
+                // again once that part is back.
+                _ => {
+                    self.pending_groups.remove(&group);
+                    continue;
+                }
+            };
+
+            let pending = self.pending_groups.get_mut(&group).unwrap();
+            if seen.iter().any(Option::is_none) || seen != pending.seen {
+                pending.seen = seen;
+                pending.since = Instant::now();
+                continue;
+            }
+            // The files an entry expands to only wait for their own settling.
+            let least = match group {
+                Group::Named(_) => GROUP_SETTLE,
+                Group::Entry(_) => Duration::ZERO,
+            };
+            let quiet = members
+                .iter()
+                .filter_map(|fd| match fd.options.settle {
+                    Settle::Stable(duration) => Some(duration),
+                    _ => None,
+                })
+                .fold(least, Duration::max);
+            let ready = pending.since.elapsed() >= quiet
+                && members.iter().all(|fd| {
+                    fd.options.settle != Settle::ExclusiveOpen || fd.can_open_exclusively()
+                });
+            if !ready {
+                continue;
+            }
+
+            self.pending_groups.remove(&group);
+            self.scheduler.submit(CopyJob::Group(group, members));
+        }
+    }
+
+    /// Mirrors `path` into every directory description it is part of. Returns whether there was
+    /// any.
+    fn mirror(&mut self, path: &Path) -> bool {
+        let mut mirrored = false;
+        let mut copies: Vec<FileDescription> = Vec::new();
+        for directory in &self.directories {
+            if let Some(to) = directory.destination(path) {
+                if path.is_dir() {
+                    if let Err(e) = directory.mirror(path) {
+                        self.send_ui_notification(UiNotification::Warning(format!(
+                            "Could not create {:?}, because: {}",
+                            to, e
+                        )));
+                    }
+                    // A directory that was moved in already has files in it.
+                    copies.extend(
+                        DirectoryDescription::files_in(path)
+                            .iter()
+                            .filter_map(|f| directory.file_description(f)),
+                    );
+                } else if let Some(fd) = directory.file_description(path) {
+                    copies.push(fd);
+                }
+                mirrored = true;
+            }
+        }
+        for fd in copies {
+            self.copy(fd);
+        }
+        mirrored
+    }
+
+    /// Copies `p` with the patterns it matches, which watches it from now on.
+    fn match_globs(&mut self, p: &Path) {
+        let mut rules: Vec<FileDescription> = Vec::new();
+        for fd in self.globs.iter().filter_map(|g| g.file_description(p)) {
+            if !rules.iter().any(|r| r.to == fd.to) {
+                rules.push(fd);
+            }
+        }
+        if rules.is_empty() {
+            return;
+        }
+        self.send_ui_notification(UiNotification::StartedWatching(p.to_path_buf()));
+        self.matched.insert(paths::key(p), rules.clone());
+        for fd in rules {
+            self.copy(fd);
+        }
+    }
+
+    pub fn handle_event(&mut self, event: DebouncedEvent) {
+        // A changed handoff marker stands for the file it marks.
+        let marked = match &event {
+            NoticeWrite(p) | Create(p) | Write(p) | Rename(_, p) => content::marked_file(p),
+            _ => None,
+        };
+        let receiving = marked
+            .map(|file| self.receiving_rules(&file))
+            .unwrap_or_default();
+        if !receiving.is_empty() {
+            for fd in receiving {
+                self.handoff(fd);
+            }
+            return;
+        }
+
+        match event {
+            NoticeWrite(p) | Write(p) => {
+                let rules = self.rules(&p);
+                // Other files in the directory of a file are ignored.
+                if rules.is_empty() {
+                    self.mirror(&p);
+                }
+                for fd in rules {
+                    self.copy(fd);
+                }
+            }
+            Create(p) => {
+                if p.is_dir() && self.leads_to_file(&p) {
+                    self.rearm(&p);
+                }
+                let rules = self.rules(&p);
+                if !rules.is_empty() {
+                    for fd in rules {
+                        self.copy(fd);
+                    }
+                    return;
+                }
+                if self.mirror(&p) {
+                    return;
+                }
+                if p.is_dir() && self.globs.iter().any(|g| p.starts_with(&g.base)) {
+                    // A directory that was moved in already has files in it, and files can be
+                    // created in a new one before it is watched.
+                    for file in DirectoryDescription::files_in(&p) {
+                        let rules = self.rules(&file);
+                        if rules.is_empty() {
+                            self.match_globs(&file);
+                        }
+                        for fd in rules {
+                            self.copy(fd);
+                        }
+                    }
+                    return;
+                }
+                self.match_globs(&p);
+            }
+            Remove(p) => {
+                if self.watched.contains(&p) {
+                    self.rearm(&p);
+                }
+                if p.exists() {
+                    // Replaced rather than removed.
+                    self.handle_event(NoticeWrite(p));
+                    return;
+                }
+                if self.files.contains_key(&paths::key(&p)) {
+                    self.send_ui_notification(UiNotification::Waiting(p.clone()));
+                }
+                self.remove(p);
+            }
+            Rename(old, new) if FileDescription::is_temporary(&old) => {
+                // Another instance finished an atomic copy to `new`.
+                if !self.rules(&new).is_empty() {
+                    self.handle_event(NoticeWrite(new));
+                } else {
+                    self.handle_event(Create(new));
+                }
+            }
+            Rename(old, new) => {
+                let renames = self.renames(&old, &new);
+                if renames.is_empty() {
+                    self.handle_event(Remove(old));
+                    self.handle_event(Create(new));
+                    return;
+                }
+                for (old_fd, new_fd) in renames {
+                    self.scheduler.cancel(&old_fd.to);
+                    self.scheduler.submit(CopyJob::Rename(old_fd, new_fd));
+                }
+                if self.matched.remove(&paths::key(&old)).is_some() {
+                    let rules: Vec<FileDescription> = self
+                        .globs
+                        .iter()
+                        .filter_map(|g| g.file_description(&new))
+                        .collect();
+                    if !rules.is_empty() {
+                        self.matched.insert(paths::key(&new), rules);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,71 +1,52 @@
 extern crate clap;
 
+extern crate blake3;
 extern crate chrono;
 extern crate console;
 extern crate ctrlc;
+extern crate glob;
 extern crate notify;
 extern crate serde;
 extern crate serde_json;
+extern crate serde_yaml;
+extern crate toml;
 
 mod configuration_reader;
+mod content;
+mod copy;
 mod file_watcher;
+mod rules;
+#[cfg(test)]
+mod test_support;
 mod ui;
 
 use configuration_reader::*;
-use console::Term;
 use std::env;
-use std::fs;
-use std::io::Read;
-use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc;
-use std::sync::mpsc::channel;
 use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
-use std::time;
-use std::time::Duration;
 
-use file_watcher::FileWatcher;
-use ui::{Tui, Ui};
+use file_watcher::{CopyLimits, FileWatcher};
+use ui::{JsonLines, Plain, Tui, Ui};
 
 use clap::{App, Arg};
 
-#[derive(Debug)]
-pub struct FileDescription {
-    pub from: PathBuf,
-    pub to: PathBuf,
-}
-
+#[derive(Clone)]
 pub enum StepInChain {
+    /// The first hop of every file.
     Start,
+    /// The last hop of every file.
     End,
+    /// The hop from the location with this name to the next location.
+    Named(String),
 }
 
-impl FileDescription {
-    pub fn copy(&self) {
-        let mut attempts: u32 = 0;
-        while attempts < 100 {
-            println!("Copy from {:?} to {:?}!", &self.from, &self.to);
-            match fs::copy(&self.from, &self.to) {
-                Ok(_) => {
-                    println!("Copy: Break!");
-                    break;
-                }
-                Err(e) => {
-                    println!("{:?}", e);
-                    attempts += 1;
-                    thread::sleep(Duration::from_millis(10));
-                }
-            };
-        }
-    }
-}
-
-impl Clone for FileDescription {
-    fn clone(&self) -> FileDescription {
-        FileDescription {
-            from: self.from.clone(),
-            to: self.to.clone(),
+impl StepInChain {
+    pub fn name(&self) -> &str {
+        match self {
+            StepInChain::Start => "start",
+            StepInChain::End => "end",
+            StepInChain::Named(name) => name,
         }
     }
 }
@@ -81,14 +62,30 @@ fn main() -> Result<(), std::io::Error> {
                 .short("f")
                 .long("file")
                 .takes_value(true)
-                .help("The configuration file, in json format. Defaults to configuration.json."),
+                .help("The configuration file, in json, toml or yaml format. Defaults to configuration.json."),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "toml", "yaml"])
+                .help("The format of the configuration file. Defaults to detecting it from the extension."),
         )
         .arg(
             Arg::with_name("step")
                 .short("s")
                 .long("step")
                 .takes_value(true)
-                .help("The step in the copy chain. Possible values are 'start' and 'end'. Defaults to 'end'."),
+                .help("The step in the copy chain. Possible values are 'start', 'end' or the name of a location, which copies from that location to the next one. Defaults to 'end'."),
+        )
+        .arg(
+            Arg::with_name("alias")
+                .short("a")
+                .long("alias")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Overrides an alias, written as name=value. Can be given more than once."),
         )
         .arg(
             Arg::with_name("use_polling")
@@ -96,6 +93,27 @@ fn main() -> Result<(), std::io::Error> {
                 .long("use_polling")
                 .help("Use polling instead of using the OS events."),
         )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .takes_value(true)
+                .value_name("n")
+                .help("The number of copies that run at the same time. Defaults to 4."),
+        )
+        .arg(
+            Arg::with_name("copies_per_device")
+                .long("copies_per_device")
+                .takes_value(true)
+                .value_name("n")
+                .help("The number of copies that write to the same device at the same time. Defaults to 2."),
+        )
+        .arg(
+            Arg::with_name("ui")
+                .long("ui")
+                .takes_value(true)
+                .possible_values(&["plain", "json", "tui"])
+                .help("How events are shown. Defaults to tui, or plain when the output isn't a terminal."),
+        )
         .arg(
             Arg::with_name("print_watched")
                 .short("w")
@@ -107,30 +125,65 @@ fn main() -> Result<(), std::io::Error> {
     let configuration_file = matches
         .value_of("configurationfile")
         .unwrap_or("configuration.json");
-    let step_in_chain_str: &str = &matches.value_of("step").unwrap_or("end").to_lowercase();
-    let step_in_chain: StepInChain = match step_in_chain_str {
+    let configuration_format: Option<ConfigurationFormat> = matches
+        .value_of("format")
+        .and_then(ConfigurationFormat::from_name);
+    let cli_aliases: Vec<String> = matches
+        .values_of("alias")
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_default();
+    let step_in_chain_str: &str = matches.value_of("step").unwrap_or("end");
+    let step_in_chain: StepInChain = match step_in_chain_str.to_lowercase().as_str() {
         "start" => StepInChain::Start,
         "end" => StepInChain::End,
-        e => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                format!("Step in chain was not recognised, get {}", e),
-            ))
-        }
+        _ => StepInChain::Named(step_in_chain_str.to_owned()),
     };
 
     let use_polling: bool = matches.is_present("use_polling");
     let print_watched: bool = matches.is_present("print_watched");
+    let ui: &str = matches
+        .value_of("ui")
+        .unwrap_or(if console::user_attended() {
+            "tui"
+        } else {
+            "plain"
+        });
+    let mut copy_limits = CopyLimits::default();
+    for (name, limit) in [
+        ("workers", &mut copy_limits.workers),
+        ("copies_per_device", &mut copy_limits.per_device),
+    ] {
+        if let Some(value) = matches.value_of(name) {
+            *limit = match value.parse::<usize>() {
+                Ok(v) if v > 0 => v,
+                _ => {
+                    let e = std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("--{} should be a number of at least 1, got {}", name, value),
+                    );
+                    eprintln!("{}", e);
+                    return Err(e);
+                }
+            };
+        }
+    }
 
     // Parse configuration
-    let (mut configuration, unparsed) = match parse_configuration(configuration_file, step_in_chain)
-    {
+    let configuration = match parse_configuration(
+        configuration_file,
+        configuration_format,
+        &step_in_chain,
+        &cli_aliases,
+    ) {
         Ok(v) => v,
         Err(e) => {
-            eprintln!("Could not parse configuration, because: {:?}", e);
-            let mut input = String::new();
-            eprintln!("Press enter to continue.");
-            std::io::stdin().read_line(&mut input)?;
+            eprintln!("Could not parse configuration, because: {}", e);
+            // Only someone at a terminal can read the error before it closes.
+            if ui == "tui" {
+                let mut input = String::new();
+                eprintln!("Press enter to continue.");
+                std::io::stdin().read_line(&mut input)?;
+            }
             return Err(e);
         }
     };
@@ -139,6 +192,18 @@ fn main() -> Result<(), std::io::Error> {
         for fd in configuration.files {
             println!("{:?} \r\n\tto {:?}", fd.from, fd.to);
         }
+        for glob in configuration.globs {
+            println!("{:?} \r\n\tto {:?}", glob.pattern.as_str(), glob.to);
+            for fd in glob.resolve() {
+                println!("\t{:?} \r\n\t\tto {:?}", fd.from, fd.to);
+            }
+        }
+        for directory in configuration.directories {
+            println!(
+                "{:?} (directory) \r\n\tto {:?}",
+                directory.from, directory.to
+            );
+        }
         return Ok(());
     }
 
@@ -148,24 +213,39 @@ fn main() -> Result<(), std::io::Error> {
     let tx_ui = tx.clone();
 
     // Set up filewatcher and ui.
-    let mut file_watcher: FileWatcher = FileWatcher::new(configuration.files, use_polling);
-    let ui_jh = Tui::start(file_watcher.get_ui_notification_receiver(), tx_ui);
+    let mut file_watcher: FileWatcher = FileWatcher::new(configuration, use_polling);
+    file_watcher.set_copy_limits(copy_limits);
+    let configuration_path = PathBuf::from(configuration_file);
+    let configuration_file = configuration_file.to_owned();
+    file_watcher.watch_configuration(
+        configuration_path,
+        Box::new(move || {
+            parse_configuration(
+                &configuration_file,
+                configuration_format,
+                &step_in_chain,
+                &cli_aliases,
+            )
+        }),
+    );
+    let notification_rx = file_watcher.get_ui_notification_receiver();
+    let ui_jh = match ui {
+        "plain" => Plain::start(notification_rx, tx_ui),
+        "json" => JsonLines::start(notification_rx, tx_ui),
+        _ => Tui::start(notification_rx, tx_ui),
+    };
 
     file_watcher = file_watcher.start();
 
-    ctrlc::set_handler(move || {
-        tx_ctrlc.send(());
-    });
+    if let Err(e) = ctrlc::set_handler(move || {
+        let _ = tx_ctrlc.send(());
+    }) {
+        eprintln!("Could not set the ctrl-c handler, because: {:?}", e);
+    }
 
-    match rx.recv() {
-        Ok(()) => {
-            file_watcher.stop();
-            println!("stopped ok");
-            ui_jh.join();
-            return Ok(());
-        }
-        Err(_) => {
-            return Ok(());
-        }
+    if let Ok(()) = rx.recv() {
+        file_watcher.stop();
+        let _ = ui_jh.join();
     }
+    Ok(())
 }
@@ -0,0 +1,350 @@
+use crate::copy::{CopyOptions, FileDescription};
+use glob::{MatchOptions, Pattern, PatternError};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Copies every file matching `pattern`. The destination is a template in which `{relpath}` is
+/// replaced by the path of the file relative to `base`, and `{filename}` by its file name.
+#[derive(Debug, Clone)]
+pub struct GlobDescription {
+    pub base: PathBuf,
+    pub pattern: Pattern,
+    pub to: String,
+    pub options: CopyOptions,
+}
+
+impl GlobDescription {
+    pub const RELPATH: &'static str = "{relpath}";
+    pub const FILENAME: &'static str = "{filename}";
+
+    pub fn new(pattern: &str, to: &str) -> Result<GlobDescription, PatternError> {
+        let mut pattern = PathBuf::from(pattern);
+        if pattern.is_relative() {
+            if let Ok(current_dir) = env::current_dir() {
+                pattern = current_dir.join(pattern);
+            }
+        }
+        let pattern = pattern.to_string_lossy().into_owned();
+
+        // The base is every directory before the first component with a wildcard in it.
+        let wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+        let base = match pattern[..wildcard].rfind(std::path::is_separator) {
+            Some(index) => PathBuf::from(&pattern[..index]),
+            None => PathBuf::from(&pattern[..wildcard]),
+        };
+
+        Ok(GlobDescription {
+            base,
+            pattern: Pattern::new(&pattern)?,
+            to: to.to_owned(),
+            options: CopyOptions::default(),
+        })
+    }
+
+    pub fn matches<P: AsRef<Path>>(&self, path: P) -> bool {
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.pattern.matches_path_with(path.as_ref(), options)
+    }
+
+    /// Returns the description to copy `path` with, if it matches the pattern.
+    pub fn file_description<P: AsRef<Path>>(&self, path: P) -> Option<FileDescription> {
+        let path = path.as_ref();
+        if !self.matches(path)
+            || FileDescription::is_temporary(path)
+            || self.options.is_companion(path)
+        {
+            return None;
+        }
+        let relpath = path.strip_prefix(&self.base).ok()?;
+        let filename = path.file_name()?;
+        let to = self
+            .to
+            .replace(GlobDescription::RELPATH, &relpath.to_string_lossy())
+            .replace(GlobDescription::FILENAME, &filename.to_string_lossy());
+        Some(FileDescription {
+            from: path.to_path_buf(),
+            to: PathBuf::from(to),
+            options: self.options.clone(),
+        })
+    }
+
+    /// Returns a description for every file that currently matches the pattern.
+    pub fn resolve(&self) -> Vec<FileDescription> {
+        let mut result: Vec<FileDescription> = Vec::new();
+        let mut directories: Vec<PathBuf> = vec![self.base.clone()];
+        while let Some(directory) = directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    directories.push(path);
+                } else if let Some(fd) = self.file_description(&path) {
+                    result.push(fd);
+                }
+            }
+        }
+        result.sort_by(|a, b| a.from.cmp(&b.from));
+        result
+    }
+}
+
+/// Mirrors the directory `from` into `to`. Only the files whose path relative to `from` matches
+/// one of the `include` patterns (or all files, if there are none) and none of the `exclude`
+/// patterns are mirrored. With `delete_extraneous`, files in `to` that don't exist in `from` are
+/// deleted as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectoryDescription {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub delete_extraneous: bool,
+    pub options: CopyOptions,
+}
+
+impl DirectoryDescription {
+    fn includes(&self, relpath: &Path) -> bool {
+        // `*` also matches separators here, so `*.pdb` excludes the pdbs in every directory.
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: false,
+            require_literal_leading_dot: false,
+        };
+        // Excluding a directory excludes everything in it.
+        let excluded = relpath
+            .ancestors()
+            .filter(|a| !a.as_os_str().is_empty())
+            .any(|a| self.exclude.iter().any(|p| p.matches_path_with(a, options)));
+        if self.options.is_companion(relpath) {
+            return false;
+        }
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|p| p.matches_path_with(relpath, options));
+        included && !excluded
+    }
+
+    /// Returns where `path` is mirrored to, if it is part of this directory and included.
+    pub fn destination<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        let relpath = path.as_ref().strip_prefix(&self.from).ok()?;
+        if relpath.as_os_str().is_empty()
+            || !self.includes(relpath)
+            || FileDescription::is_temporary(relpath)
+        {
+            return None;
+        }
+        Some(self.to.join(relpath))
+    }
+
+    /// Returns the description to copy `path` with, if it is part of this directory.
+    pub fn file_description<P: AsRef<Path>>(&self, path: P) -> Option<FileDescription> {
+        self.destination(&path).map(|to| FileDescription {
+            from: path.as_ref().to_path_buf(),
+            to,
+            options: self.options.clone(),
+        })
+    }
+
+    /// Copies `path` to its mirror, or creates the mirror if it is a directory.
+    pub fn mirror<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        if path.as_ref().is_dir() {
+            if let Some(to) = self.destination(&path) {
+                fs::create_dir_all(to)?;
+            }
+        } else if let Some(fd) = self.file_description(path) {
+            fd.copy()?;
+        }
+        Ok(())
+    }
+
+    /// Every file in `directory` and the directories in it.
+    pub fn files_in(directory: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        let mut directories: Vec<PathBuf> = vec![directory.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let entries = match fs::read_dir(&directory) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    directories.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    /// Brings `to` in line with `from` when mirroring starts: with `delete_extraneous`, files
+    /// that are missing from `from` are deleted. Returns the copies of every file in `from`,
+    /// which are left to the caller. Like any other copy, they skip the destinations that are
+    /// unchanged, so a destination that is out of date is copied again as well. The files that
+    /// couldn't be deleted are added to `failures`.
+    pub fn synchronize(
+        &self,
+        failures: &mut Vec<(PathBuf, std::io::Error)>,
+    ) -> Vec<FileDescription> {
+        let copies: Vec<FileDescription> = DirectoryDescription::files_in(&self.from)
+            .iter()
+            .filter_map(|path| self.file_description(path))
+            .collect();
+        if !self.delete_extraneous {
+            return copies;
+        }
+        for to in DirectoryDescription::files_in(&self.to) {
+            let relpath = match to.strip_prefix(&self.to) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            if self.includes(relpath) && !self.from.join(relpath).exists() {
+                if let Err(e) = fs::remove_file(&to) {
+                    failures.push((to, e));
+                }
+            }
+        }
+        copies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::{CopyOutcome, RemovePolicy, RenamePolicy};
+    use crate::test_support::TempDir;
+    use std::fs::File;
+
+    #[test]
+    fn test_glob_description() {
+        let base = TempDir::new("glob_description");
+        fs::create_dir_all(base.join("bin").join("plugins")).unwrap();
+        File::create(base.join("bin").join("app.dll")).unwrap();
+        File::create(base.join("bin").join("app.txt")).unwrap();
+        File::create(base.join("bin").join("plugins").join("plugin.dll")).unwrap();
+
+        let pattern = base.join("bin").join("**").join("*.dll");
+        let to = base.join("share").join(GlobDescription::RELPATH);
+        let glob = GlobDescription::new(&pattern.to_string_lossy(), &to.to_string_lossy()).unwrap();
+        assert_eq!(base.join("bin"), glob.base);
+
+        let files = glob.resolve();
+        assert_eq!(files.len(), 2);
+        assert_eq!(base.join("bin").join("app.dll"), files[0].from);
+        assert_eq!(base.join("share").join("app.dll"), files[0].to);
+        assert_eq!(
+            base.join("bin").join("plugins").join("plugin.dll"),
+            files[1].from
+        );
+        assert_eq!(
+            base.join("share").join("plugins").join("plugin.dll"),
+            files[1].to
+        );
+
+        // Files that don't exist yet are matched as well.
+        let fd = glob
+            .file_description(base.join("bin").join("new.dll"))
+            .unwrap();
+        assert_eq!(base.join("share").join("new.dll"), fd.to);
+        assert!(glob
+            .file_description(base.join("bin").join("new.txt"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_directory_description() {
+        let base = TempDir::new("directory_description");
+        fs::create_dir_all(base.join("bin").join("obj")).unwrap();
+        fs::create_dir_all(base.join("share")).unwrap();
+        File::create(base.join("bin").join("app.dll")).unwrap();
+        File::create(base.join("bin").join("app.pdb")).unwrap();
+        File::create(base.join("bin").join("obj").join("app.obj")).unwrap();
+        File::create(base.join("share").join("old.dll")).unwrap();
+        File::create(base.join("share").join("old.pdb")).unwrap();
+
+        let directory = DirectoryDescription {
+            from: base.join("bin"),
+            to: base.join("share"),
+            include: Vec::new(),
+            exclude: vec![Pattern::new("*.pdb").unwrap(), Pattern::new("obj").unwrap()],
+            delete_extraneous: true,
+            options: CopyOptions {
+                on_remove: RemovePolicy::Delete,
+                on_rename: RenamePolicy::Rename,
+                ..CopyOptions::default()
+            },
+        };
+        assert_eq!(
+            Some(base.join("share").join("app.dll")),
+            directory.destination(base.join("bin").join("app.dll"))
+        );
+        assert_eq!(
+            None,
+            directory.destination(base.join("bin").join("app.pdb"))
+        );
+        assert_eq!(
+            None,
+            directory.destination(base.join("other").join("app.dll"))
+        );
+
+        let mut failures = Vec::new();
+        let copies = directory.synchronize(&mut failures);
+        assert!(failures.is_empty());
+        assert_eq!(copies.len(), 1);
+        for fd in copies {
+            assert_eq!(CopyOutcome::Copied(1), fd.copy().unwrap());
+        }
+        // Starting again skips what is already there, but not what is out of date.
+        assert_eq!(
+            CopyOutcome::Skipped,
+            directory.synchronize(&mut failures)[0].copy().unwrap()
+        );
+        fs::write(base.join("share").join("app.dll"), b"Outdated").unwrap();
+        assert_eq!(
+            CopyOutcome::Copied(1),
+            directory.synchronize(&mut failures)[0].copy().unwrap()
+        );
+        assert!(base.join("share").join("app.dll").exists());
+        assert!(!base.join("share").join("app.pdb").exists());
+        assert!(!base.join("share").join("obj").exists());
+        // Excluded files are left alone, even when they are extraneous.
+        assert!(!base.join("share").join("old.dll").exists());
+        assert!(base.join("share").join("old.pdb").exists());
+
+        fs::rename(
+            base.join("bin").join("app.dll"),
+            base.join("bin").join("new.dll"),
+        )
+        .unwrap();
+        let old = directory
+            .file_description(base.join("bin").join("app.dll"))
+            .unwrap();
+        directory
+            .file_description(base.join("bin").join("new.dll"))
+            .unwrap()
+            .take_over(&old)
+            .unwrap();
+        assert!(!base.join("share").join("app.dll").exists());
+        assert!(base.join("share").join("new.dll").exists());
+
+        fs::remove_file(base.join("bin").join("new.dll")).unwrap();
+        directory
+            .file_description(base.join("bin").join("new.dll"))
+            .unwrap()
+            .remove_destination()
+            .unwrap();
+        assert!(!base.join("share").join("new.dll").exists());
+    }
+}
@@ -0,0 +1,70 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a test waits for the watcher to do something, before it fails.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An empty directory that no other test uses, not even one of another run of the tests at the
+/// same time. It is removed again when it is dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "autocopier_{}_{}_{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Waits until `condition` holds, and fails the test if it doesn't within `timeout`.
+#[track_caller]
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(
+            started.elapsed() < timeout,
+            "the condition didn't hold within {:?}",
+            timeout
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Whether the file at `path` has `contents`.
+pub fn has_contents(path: &Path, contents: &[u8]) -> bool {
+    fs::read(path).map(|v| v == contents).unwrap_or(false)
+}
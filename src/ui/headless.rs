@@ -0,0 +1,211 @@
+use crate::file_watcher::notifications::UiNotification;
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+
+use super::Ui;
+
+/// Writes a line of text for every event, without any terminal control, for running under a
+/// service manager or with the output going to a file.
+pub struct Plain {}
+
+/// Writes a JSON object on its own line for every event.
+pub struct JsonLines {}
+
+/// A notification as the name of its event, its fields and a sentence.
+struct Event {
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+    text: String,
+}
+
+fn path(p: &Path) -> String {
+    p.to_string_lossy().into_owned()
+}
+
+impl Event {
+    fn new(notification: &UiNotification) -> Event {
+        let (name, fields, text) = match notification {
+            UiNotification::Started => ("started", vec![], String::from("Started")),
+            UiNotification::StartedWatching(pb) => (
+                "started_watching",
+                vec![("path", path(pb))],
+                format!("Started watching {:?}", pb),
+            ),
+            UiNotification::StoppedWatching(pb) => (
+                "stopped_watching",
+                vec![("path", path(pb))],
+                format!("Stopped watching {:?}", pb),
+            ),
+            UiNotification::Waiting(pb) => (
+                "waiting",
+                vec![("path", path(pb))],
+                format!("Waiting for {:?}", pb),
+            ),
+            UiNotification::Queued(from, to) => (
+                "queued",
+                vec![("from", path(from)), ("to", path(to))],
+                format!("Queued {:?} to {:?}", from, to),
+            ),
+            UiNotification::Copying(from, to) => (
+                "copying",
+                vec![("from", path(from)), ("to", path(to))],
+                format!("Copying {:?} to {:?}", from, to),
+            ),
+            UiNotification::Copied(from, to) => (
+                "copied",
+                vec![("from", path(from)), ("to", path(to))],
+                format!("Copied {:?} to {:?}", from, to),
+            ),
+            UiNotification::Skipped(from, to) => (
+                "skipped",
+                vec![("from", path(from)), ("to", path(to))],
+                format!("Skipped {:?}, {:?} is the same", from, to),
+            ),
+            UiNotification::CopyFailed(from, to, e) => (
+                "copy_failed",
+                vec![
+                    ("from", path(from)),
+                    ("to", path(to)),
+                    ("error", e.to_owned()),
+                ],
+                format!("Could not copy {:?} to {:?}, because: {}", from, to, e),
+            ),
+            UiNotification::Cancelled(from, to) => (
+                "cancelled",
+                vec![("from", path(from)), ("to", path(to))],
+                format!("Cancelled copying {:?} to {:?}", from, to),
+            ),
+            UiNotification::Removed(pb) => (
+                "removed",
+                vec![("path", path(pb))],
+                format!("Removed {:?}", pb),
+            ),
+            UiNotification::Quarantined(from, to) => (
+                "quarantined",
+                vec![("from", path(from)), ("to", path(to))],
+                format!("Quarantined {:?} as {:?}", from, to),
+            ),
+            UiNotification::Renamed(old, new) => (
+                "renamed",
+                vec![("old", path(old)), ("new", path(new))],
+                format!("Renamed {:?} to {:?}", old, new),
+            ),
+            UiNotification::ConfigurationReloaded => (
+                "configuration_reloaded",
+                vec![],
+                String::from("Reloaded the configuration"),
+            ),
+            UiNotification::ConfigurationError(e) => (
+                "configuration_error",
+                vec![("error", e.to_owned())],
+                format!("Could not reload the configuration, because: {}", e),
+            ),
+            UiNotification::Warning(message) => (
+                "warning",
+                vec![("message", message.to_owned())],
+                message.to_owned(),
+            ),
+        };
+        Event { name, fields, text }
+    }
+
+    /// The event as text, on a single line.
+    fn to_plain(&self, time: &str) -> String {
+        format!("{} {}", time, self.text.replace('\n', " "))
+    }
+
+    /// The event as a JSON object, on a single line.
+    fn to_json(&self, time: &str) -> String {
+        let mut object = Map::new();
+        object.insert(String::from("time"), Value::from(time));
+        object.insert(String::from("event"), Value::from(self.name));
+        for (name, value) in &self.fields {
+            object.insert(String::from(*name), Value::from(value.as_str()));
+        }
+        Value::Object(object).to_string()
+    }
+}
+
+/// Writes every notification with `format` until the file watcher stops. There are no
+/// keystrokes to read, so stopping is left to ctrl-c and the service manager.
+fn start(
+    notification_rx: Receiver<UiNotification>,
+    format: fn(&Event, &str) -> String,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(notification) = notification_rx.recv() {
+            let time = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+            println!("{}", format(&Event::new(&notification), &time));
+        }
+    })
+}
+
+impl Ui for Plain {
+    fn start(
+        notification_rx: Receiver<UiNotification>,
+        _termination_tx: Sender<()>,
+    ) -> JoinHandle<()> {
+        start(notification_rx, Event::to_plain)
+    }
+}
+
+impl Ui for JsonLines {
+    fn start(
+        notification_rx: Receiver<UiNotification>,
+        _termination_tx: Sender<()>,
+    ) -> JoinHandle<()> {
+        start(notification_rx, Event::to_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_event_lines() {
+        let time = "2024-01-01T12:00:00.000+01:00";
+        let failed = Event::new(&UiNotification::CopyFailed(
+            PathBuf::from("bin/app.exe"),
+            PathBuf::from("share/app.exe"),
+            String::from("Access is denied.\nTried 3 times"),
+        ));
+        assert_eq!(
+            "2024-01-01T12:00:00.000+01:00 Could not copy \"bin/app.exe\" to \"share/app.exe\", \
+             because: Access is denied. Tried 3 times",
+            failed.to_plain(time)
+        );
+
+        let line = failed.to_json(time);
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("copy_failed", value["event"]);
+        assert_eq!(time, value["time"]);
+        assert_eq!("bin/app.exe", value["from"]);
+        assert_eq!("share/app.exe", value["to"]);
+        assert_eq!("Access is denied.\nTried 3 times", value["error"]);
+
+        let value: Value =
+            serde_json::from_str(&Event::new(&UiNotification::Started).to_json(time)).unwrap();
+        assert_eq!("started", value["event"]);
+
+        let warning = Event::new(&UiNotification::Warning(String::from(
+            "Couldn't watch \"bin\", because: No such file or directory",
+        )));
+        assert_eq!(
+            "2024-01-01T12:00:00.000+01:00 Couldn't watch \"bin\", because: No such file or \
+             directory",
+            warning.to_plain(time)
+        );
+        let value: Value = serde_json::from_str(&warning.to_json(time)).unwrap();
+        assert_eq!("warning", value["event"]);
+        assert_eq!(
+            "Couldn't watch \"bin\", because: No such file or directory",
+            value["message"]
+        );
+    }
+}
@@ -1,14 +1,16 @@
 use crate::file_watcher::notifications::UiNotification;
-use chrono::offset::Local;
-use chrono::DateTime;
 use console::style;
 use console::Term;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{Receiver, RecvError, Sender, TryRecvError};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
 use std::thread::JoinHandle;
 use std::time::{Duration, SystemTime};
-use std::{fs, thread};
+
+mod headless;
+
+pub use headless::{JsonLines, Plain};
 
 pub trait Ui {
     fn start(
@@ -19,57 +21,219 @@ pub trait Ui {
 
 pub struct Tui {}
 
+/// What is happening to a copy that hasn't ended yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Activity {
+    Queued,
+    Copying,
+}
+
 struct TuiInformation {
     map: HashMap<PathBuf, Option<SystemTime>>,
+    /// When every destination of a source was last copied to.
+    destinations: HashMap<PathBuf, BTreeMap<PathBuf, Option<SystemTime>>>,
+    /// The sources that don't exist, until they are copied.
+    waiting: HashSet<PathBuf>,
+    /// Why the last copy of a source to a destination failed, until it succeeds again.
+    failures: HashMap<(PathBuf, PathBuf), String>,
+    /// The copies from a source to a destination that are queued or running.
+    activities: HashMap<(PathBuf, PathBuf), Activity>,
+    /// Why the configuration file couldn't be reloaded, until it can be.
+    configuration_error: Option<String>,
+    /// The last warning, until the next one.
+    warning: Option<String>,
     last_output: String,
 }
 
+impl TuiInformation {
+    /// The last time `from` was copied to `to`, which is added as not yet copied if it is new.
+    fn destination(&mut self, from: &Path, to: PathBuf) -> &mut Option<SystemTime> {
+        self.destinations
+            .entry(from.to_path_buf())
+            .or_default()
+            .entry(to)
+            .or_insert(None)
+    }
+
+    /// Forgets everything about the source `from`.
+    fn forget(&mut self, from: &Path) {
+        self.map.remove(from);
+        self.destinations.remove(from);
+        self.waiting.remove(from);
+        self.failures.retain(|(source, _), _| source != from);
+        self.activities.retain(|(source, _), _| source != from);
+    }
+
+    /// Remembers that the copy from `from` to `to` ended.
+    fn ended(&mut self, from: &Path, to: &Path) {
+        self.waiting.remove(from);
+        self.activities
+            .remove(&(from.to_path_buf(), to.to_path_buf()));
+    }
+
+    /// Updates what is shown with `notification`.
+    fn handle(&mut self, notification: UiNotification) {
+        match notification {
+            UiNotification::Started => {}
+            UiNotification::StartedWatching(pb) => {
+                self.map.insert(pb, None);
+            }
+            UiNotification::StoppedWatching(pb) => {
+                self.forget(&pb);
+            }
+            UiNotification::Waiting(pb) => {
+                self.map.entry(pb.clone()).or_insert(None);
+                // A copy that was queued before the source disappeared won't run.
+                self.activities.retain(|(source, _), _| *source != pb);
+                self.waiting.insert(pb);
+            }
+            UiNotification::Queued(from, to) => {
+                self.destination(&from, to.clone());
+                self.map.entry(from.clone()).or_insert(None);
+                self.activities.insert((from, to), Activity::Queued);
+            }
+            UiNotification::Copying(from, to) => {
+                self.destination(&from, to.clone());
+                self.map.entry(from.clone()).or_insert(None);
+                self.activities.insert((from, to), Activity::Copying);
+            }
+            UiNotification::Copied(from, to) => {
+                self.ended(&from, &to);
+                let now = Some(SystemTime::now());
+                self.failures.remove(&(from.clone(), to.clone()));
+                *self.destination(&from, to) = now;
+                self.map.insert(from, now);
+            }
+            UiNotification::Skipped(from, to) => {
+                self.ended(&from, &to);
+                self.failures.remove(&(from.clone(), to.clone()));
+                self.destination(&from, to);
+                self.map.entry(from).or_insert(None);
+            }
+            UiNotification::CopyFailed(from, to, e) => {
+                self.ended(&from, &to);
+                self.destination(&from, to.clone());
+                self.map.entry(from.clone()).or_insert(None);
+                self.failures.insert((from, to), e);
+            }
+            UiNotification::Cancelled(from, to) => {
+                self.ended(&from, &to);
+            }
+            UiNotification::Removed(pb) => {
+                self.forget(&pb);
+            }
+            UiNotification::Quarantined(from, _) => {
+                self.forget(&from);
+            }
+            UiNotification::Renamed(old, new) => {
+                let last_copied = self.map.remove(&old).flatten();
+                self.forget(&old);
+                self.map
+                    .insert(new, last_copied.or_else(|| Some(SystemTime::now())));
+            }
+            UiNotification::ConfigurationReloaded => {
+                self.configuration_error = None;
+            }
+            UiNotification::ConfigurationError(e) => {
+                self.configuration_error = Some(e);
+            }
+            UiNotification::Warning(message) => {
+                self.warning = Some(message);
+            }
+        }
+    }
+}
+
 impl Tui {
+    /// Shows `name` in the colour of how long ago `last_copied` was.
+    fn styled(name: &str, last_copied: &Option<SystemTime>) -> String {
+        let st = match last_copied {
+            Some(v) => v,
+            None => return format!("{} (not yet)", style(name).white()),
+        };
+        let elapsed = SystemTime::now().duration_since(*st).unwrap_or_default();
+        if elapsed < Duration::from_secs(10) {
+            format!("{} (just now)", style(name).green())
+        } else if elapsed < Duration::from_secs(60) {
+            format!("{} (< 1 minute)", style(name).yellow())
+        } else if elapsed < Duration::from_secs(10 * 60) {
+            format!("{} (< 10 minutes)", style(name).blue())
+        } else {
+            format!("{} (> 10 minutes)", style(name).white())
+        }
+    }
+
+    fn styled_activity(activity: Option<&Activity>) -> String {
+        match activity {
+            Some(Activity::Queued) => format!(" {}", style("queued").cyan()),
+            Some(Activity::Copying) => format!(" {}", style("copying").cyan()),
+            None => String::new(),
+        }
+    }
+
     fn redraw_screen(term: Term, info: &mut TuiInformation) {
         let mut to_output: String = String::new();
         for (key, val) in &info.map {
-            match val {
-                Some(st) => {
-                    if (SystemTime::now().duration_since(*st).unwrap() < Duration::from_secs(10)) {
-                        to_output.push_str(&format!(
-                            "{} (just now)",
-                            style(key.to_str().unwrap()).green()
-                        ));
-                    } else if (SystemTime::now().duration_since(*st).unwrap()
-                        < Duration::from_secs(60))
-                    {
-                        to_output.push_str(&format!(
-                            "{} (< 1 minute)",
-                            style(key.to_str().unwrap()).yellow()
-                        ));
-                    } else if (SystemTime::now().duration_since(*st).unwrap()
-                        < Duration::from_secs(10 * 60))
-                    {
-                        to_output.push_str(&format!(
-                            "{} (< 10 minutes)",
-                            style(key.to_str().unwrap()).blue()
-                        ));
-                    } else {
-                        to_output.push_str(&format!(
-                            "{} (> 10 minutes)",
-                            style(key.to_str().unwrap()).white()
+            if info.waiting.contains(key) {
+                to_output.push_str(&format!(
+                    "{} (waiting for file)",
+                    style(key.to_str().unwrap()).white()
+                ));
+            } else {
+                to_output.push_str(&Tui::styled(key.to_str().unwrap(), val));
+            }
+            let destinations = info.destinations.get(key);
+            match destinations {
+                // A source copied to several destinations gets a line for each of them.
+                Some(destinations) if destinations.len() > 1 => {
+                    for (to, last_copied) in destinations {
+                        to_output.push_str("\r\n  -> ");
+                        to_output.push_str(&Tui::styled(to.to_str().unwrap(), last_copied));
+                        to_output.push_str(&Tui::styled_activity(
+                            info.activities.get(&(key.clone(), to.clone())),
                         ));
+                        if let Some(e) = info.failures.get(&(key.clone(), to.clone())) {
+                            to_output
+                                .push_str(&format!(" {}", style(format!("failed: {}", e)).red()));
+                        }
                     }
                 }
-                None => {
-                    to_output.push_str(&format!(
-                        "{} (not yet)",
-                        style(key.to_str().unwrap()).white()
-                    ));
+                _ => {
+                    let activity = info
+                        .activities
+                        .iter()
+                        .find(|((from, _), _)| from == key)
+                        .map(|(_, a)| a);
+                    to_output.push_str(&Tui::styled_activity(activity));
+                    let failure = info
+                        .failures
+                        .iter()
+                        .find(|((from, _), _)| from == key)
+                        .map(|(_, e)| e);
+                    if let Some(e) = failure {
+                        to_output.push_str(&format!(" {}", style(format!("failed: {}", e)).red()));
+                    }
                 }
             }
             to_output.push_str("\r\n");
         }
+        if let Some(e) = &info.configuration_error {
+            to_output.push_str(&format!(
+                "{}\r\n",
+                style(format!("Configuration not reloaded: {}", e).replace('\n', "\r\n")).red()
+            ));
+        }
+        if let Some(message) = &info.warning {
+            to_output.push_str(&format!(
+                "{}\r\n",
+                style(message.replace('\n', "\r\n")).yellow()
+            ));
+        }
         to_output.push_str("Press q to stop.\r\n");
 
-        if (to_output != info.last_output) {
-            term.clear_screen();
-            term.write_line(&to_output);
+        if to_output != info.last_output {
+            let _ = term.clear_screen();
+            let _ = term.write_line(&to_output);
             info.last_output = to_output;
         }
     }
@@ -82,7 +246,7 @@ impl Ui for Tui {
     ) -> JoinHandle<()> {
         thread::spawn(move || {
             let term = Term::stdout();
-            term.clear_line();
+            let _ = term.clear_line();
             let term_clone = term.clone();
 
             thread::spawn(move || {
@@ -90,11 +254,11 @@ impl Ui for Tui {
                 loop {
                     match term_clone.read_char() {
                         Ok('q') => {
-                            termination_tx.send(());
+                            let _ = termination_tx.send(());
                             break;
                         }
                         _ => {
-                            term_clone.write_line("Not recognized.");
+                            let _ = term_clone.write_line("Not recognized.");
                         }
                     }
                 }
@@ -103,35 +267,28 @@ impl Ui for Tui {
             // TuiInformation
             let mut tui_information: TuiInformation = TuiInformation {
                 map: HashMap::new(),
+                destinations: HashMap::new(),
+                waiting: HashSet::new(),
+                failures: HashMap::new(),
+                activities: HashMap::new(),
+                configuration_error: None,
+                warning: None,
                 last_output: String::from(""),
             };
 
             loop {
-                match notification_rx.try_recv() {
-                    Ok(UiNotification::Started) => {
-                        println!("We started!");
-                    }
-                    Ok(UiNotification::StartedWatching(pb)) => {
-                        println!("Started watching {:?}", pb);
-                        tui_information.map.insert(pb, None);
-                    }
-                    Ok(UiNotification::StoppedWatching(pb)) => {
-                        println!("Stopped watching {:?}", pb);
-                        tui_information.map.remove(&pb);
-                    }
-                    Ok(UiNotification::Copied(from, to)) => {
-                        println!("Copied {:?} to {:?}", from, to);
-                        tui_information.map.insert(from, Some(SystemTime::now()));
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => {
-                        // Disconnected, so we just stop.
-                        println!("Disconnected");
-                        break;
+                // Everything that happened since the last redraw is shown at once.
+                match notification_rx.recv_timeout(Duration::from_millis(250)) {
+                    Ok(notification) => {
+                        tui_information.handle(notification);
+                        for notification in notification_rx.try_iter() {
+                            tui_information.handle(notification);
+                        }
                     }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
                 Tui::redraw_screen(term.clone(), &mut tui_information);
-                thread::sleep(Duration::from_millis(250));
             }
         })
     }